@@ -0,0 +1,246 @@
+// 📊 [烧钱速率告警] 在硬性预算线之外，再加一层"趋势"告警
+//
+// 之前只有 `current_cost >= limit` 的硬熔断（`PAYMENT_REQUIRED`），对调用方来说
+// 是一个没有任何预兆的悬崖。这里按租户维护一个滑动窗口：每次计费后喂入最新的
+// 累计成本，换算成"每分钟烧钱速率"，用简单的 EWMA 做一条趋势线，触发两类事件：
+//   - approaching_budget：累计成本达到限额的 N%（默认 80%）
+//   - spend_rate_spike：当前速率超过趋势线的 M 倍（默认 3 倍），说明正在"加速烧钱"
+// 事件通过既有的 `ws_tx` 广播给灵动岛前端，并可选地 POST 到用户配置的 webhook。
+
+use dashmap::DashMap;
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 同一租户同一类告警的最短触发间隔，避免在阈值附近反复横跳刷屏
+const APPROACHING_DEBOUNCE: Duration = Duration::from_secs(60);
+const SPIKE_DEBOUNCE: Duration = Duration::from_secs(30);
+
+/// EWMA 平滑系数：越大越跟随最近速率，越小越平滑
+const EWMA_ALPHA: f64 = 0.3;
+
+/// 可配置的告警阈值，默认值对应请求里描述的"80% 预警 / 3 倍突增"
+pub struct AlertThresholds {
+    pub approaching_ratio: f64,
+    pub spike_multiplier: f64,
+}
+
+impl Default for AlertThresholds {
+    fn default() -> Self {
+        AlertThresholds {
+            approaching_ratio: 0.8,
+            spike_multiplier: 3.0,
+        }
+    }
+}
+
+/// 单个租户的滑动窗口状态：上一次采样点 + EWMA 趋势线 + 各类告警的去抖时间戳
+struct TenantAlertState {
+    last_cost: f64,
+    last_sample_at: Instant,
+    ewma_per_min: f64,
+    last_approaching_fired_at: Option<Instant>,
+    last_spike_fired_at: Option<Instant>,
+}
+
+pub struct AlertEngine {
+    thresholds: Mutex<AlertThresholds>,
+    webhook_url: Mutex<Option<String>>,
+    tenants: DashMap<String, TenantAlertState>,
+}
+
+impl AlertEngine {
+    pub fn new() -> Self {
+        AlertEngine {
+            thresholds: Mutex::new(AlertThresholds::default()),
+            webhook_url: Mutex::new(None),
+            tenants: DashMap::new(),
+        }
+    }
+
+    /// `POST /v1/config/alerts` 的落地点：任意字段缺省时保留原值
+    pub fn configure(&self, approaching_ratio: Option<f64>, spike_multiplier: Option<f64>, webhook_url: Option<String>) {
+        let mut thresholds = self.thresholds.lock().unwrap();
+        if let Some(ratio) = approaching_ratio {
+            thresholds.approaching_ratio = ratio;
+        }
+        if let Some(multiplier) = spike_multiplier {
+            thresholds.spike_multiplier = multiplier;
+        }
+        drop(thresholds);
+
+        if let Some(url) = webhook_url {
+            *self.webhook_url.lock().unwrap() = if url.is_empty() { None } else { Some(url) };
+        }
+    }
+
+    pub fn webhook_url(&self) -> Option<String> {
+        self.webhook_url.lock().unwrap().clone()
+    }
+
+    /// 每次计费更新之后调用：喂入最新的累计成本，按需产生 0~2 条告警事件
+    pub fn sample(&self, tenant_id: &str, current_cost: f64, limit: f64, model: &str) -> Vec<Value> {
+        let mut events = Vec::new();
+        let now = Instant::now();
+
+        let mut state = self.tenants.entry(tenant_id.to_string()).or_insert_with(|| TenantAlertState {
+            last_cost: current_cost,
+            last_sample_at: now,
+            ewma_per_min: 0.0,
+            last_approaching_fired_at: None,
+            last_spike_fired_at: None,
+        });
+
+        let elapsed_secs = now.duration_since(state.last_sample_at).as_secs_f64().max(0.001);
+        let delta_cost = (current_cost - state.last_cost).max(0.0);
+        let rate_per_min = delta_cost / elapsed_secs * 60.0;
+
+        // 突增判断要和本次采样之前的趋势线比，先记下它，再把本次速率并入 EWMA
+        let trailing_per_min = state.ewma_per_min;
+
+        // 冷启动：第一次采样直接拿当前速率做种子，避免 EWMA=0 时任何非零速率都被误判为"突增"
+        if trailing_per_min == 0.0 {
+            state.ewma_per_min = rate_per_min;
+        } else {
+            state.ewma_per_min = EWMA_ALPHA * rate_per_min + (1.0 - EWMA_ALPHA) * state.ewma_per_min;
+        }
+
+        let (approaching_ratio, spike_multiplier) = {
+            let t = self.thresholds.lock().unwrap();
+            (t.approaching_ratio, t.spike_multiplier)
+        };
+
+        let projected_exhaustion_secs = if state.ewma_per_min > 0.0 {
+            Some(((limit - current_cost).max(0.0)) / (state.ewma_per_min / 60.0))
+        } else {
+            None
+        };
+
+        // 🟡 80% 预警：越过阈值但还没触发硬熔断时，每个租户每 60s 最多提醒一次
+        if limit > 0.0 && current_cost >= limit * approaching_ratio && current_cost < limit {
+            let should_fire = state
+                .last_approaching_fired_at
+                .map(|t| now.duration_since(t) >= APPROACHING_DEBOUNCE)
+                .unwrap_or(true);
+            if should_fire {
+                state.last_approaching_fired_at = Some(now);
+                events.push(json!({
+                    "type": "alert",
+                    "kind": "approaching_budget",
+                    "tenant_id": tenant_id,
+                    "model": model,
+                    "cost": current_cost,
+                    "limit": limit,
+                    "ratio": current_cost / limit,
+                    "projected_exhaustion_secs": projected_exhaustion_secs,
+                }));
+            }
+        }
+
+        // 🔴 烧钱突增：当前速率超过趋势线的 N 倍（趋势线太小时不判断，否则零基数下任何速率都算突增）
+        if trailing_per_min > 0.01 && rate_per_min > trailing_per_min * spike_multiplier {
+            let should_fire = state
+                .last_spike_fired_at
+                .map(|t| now.duration_since(t) >= SPIKE_DEBOUNCE)
+                .unwrap_or(true);
+            if should_fire {
+                state.last_spike_fired_at = Some(now);
+                events.push(json!({
+                    "type": "alert",
+                    "kind": "spend_rate_spike",
+                    "tenant_id": tenant_id,
+                    "model": model,
+                    "cost": current_cost,
+                    "limit": limit,
+                    "burn_rate_per_min": rate_per_min,
+                    "trailing_avg_per_min": trailing_per_min,
+                    "projected_exhaustion_secs": projected_exhaustion_secs,
+                }));
+            }
+        }
+
+        state.last_cost = current_cost;
+        state.last_sample_at = now;
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(events: &[Value]) -> Vec<&str> {
+        events.iter().filter_map(|e| e["kind"].as_str()).collect()
+    }
+
+    #[test]
+    fn cold_start_seeds_ewma_without_firing_a_false_spike() {
+        let engine = AlertEngine::new();
+        // 第一次采样只是建立起点，delta 必然是 0
+        engine.sample("t1", 100.0, 1_000_000.0, "m");
+
+        // 第二次采样 ewma 仍是 0（冷启动分支），即便这次速率很猛，也只是拿来当种子，不应判定为突增
+        let events = engine.sample("t1", 100_000.0, 1_000_000.0, "m");
+        assert!(!kinds(&events).contains(&"spend_rate_spike"));
+    }
+
+    #[test]
+    fn spike_fires_once_then_debounces() {
+        let engine = AlertEngine::new();
+        // 手动把某租户的趋势线钉死成一个很小的已知值，跳过冷启动分支
+        engine.tenants.insert("t1".to_string(), TenantAlertState {
+            last_cost: 0.0,
+            last_sample_at: Instant::now(),
+            ewma_per_min: 1.0,
+            last_approaching_fired_at: None,
+            last_spike_fired_at: None,
+        });
+
+        // 远超趋势线 3 倍的猛增，应该触发 spend_rate_spike
+        let events = engine.sample("t1", 1_000_000.0, 1_000_000_000.0, "m");
+        assert!(kinds(&events).contains(&"spend_rate_spike"));
+
+        // 去抖窗口（30s）内紧接着再来一次同样猛烈的增长，不应该重复告警
+        let events = engine.sample("t1", 2_000_000.0, 1_000_000_000.0, "m");
+        assert!(!kinds(&events).contains(&"spend_rate_spike"));
+
+        // 把上次告警时间拨到去抖窗口之外，并把趋势线钉回很小的已知值（前两次猛增已被 EWMA 吸收），
+        // 同样的猛增应该能再次触发
+        {
+            let mut state = engine.tenants.get_mut("t1").unwrap();
+            state.last_spike_fired_at = Some(Instant::now() - SPIKE_DEBOUNCE - Duration::from_secs(1));
+            state.ewma_per_min = 1.0;
+        }
+        let events = engine.sample("t1", 3_000_000.0, 1_000_000_000.0, "m");
+        assert!(kinds(&events).contains(&"spend_rate_spike"));
+    }
+
+    #[test]
+    fn approaching_budget_fires_once_then_debounces() {
+        let engine = AlertEngine::new();
+
+        // 85% 越过默认 80% 阈值但还没到熔断线
+        let events = engine.sample("t1", 85.0, 100.0, "m");
+        assert!(kinds(&events).contains(&"approaching_budget"));
+
+        // 去抖窗口（60s）内紧接着再采样一次，不应该重复提醒
+        let events = engine.sample("t1", 86.0, 100.0, "m");
+        assert!(!kinds(&events).contains(&"approaching_budget"));
+
+        // 拨到去抖窗口之外，应该能再次提醒
+        {
+            let mut state = engine.tenants.get_mut("t1").unwrap();
+            state.last_approaching_fired_at = Some(Instant::now() - APPROACHING_DEBOUNCE - Duration::from_secs(1));
+        }
+        let events = engine.sample("t1", 87.0, 100.0, "m");
+        assert!(kinds(&events).contains(&"approaching_budget"));
+    }
+
+    #[test]
+    fn approaching_budget_does_not_fire_once_hard_limit_is_reached() {
+        let engine = AlertEngine::new();
+        let events = engine.sample("t1", 100.0, 100.0, "m");
+        assert!(!kinds(&events).contains(&"approaching_budget"));
+    }
+}
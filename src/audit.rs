@@ -0,0 +1,365 @@
+// 🧾 [审计导出] 把每次请求的计费明细异步推送到 Elasticsearch 兼容的 `_bulk` 端点，
+// 同时追加写入本地可压缩归档的审计日志，供 `GET /analytics` 按时间桶离线复盘
+//
+// 取代原来的 `println!` 调试输出：这里把记录塞进一个有界 channel，后台任务按
+// "攒够 N 条 或 到达时间间隔" 的策略批量 flush，绝不阻塞计费/流式主路径。
+// 没有配置 `AUDIT_SINK_URL` 时 ES 推送原样跑，只是后台任务把记录丢弃；本地归档日志
+// 不依赖任何外部服务，始终落盘到 `AUDIT_LOG_DIR`（默认 `audit_logs/`）。
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+const CHANNEL_CAPACITY: usize = 1024;
+const FLUSH_BATCH_SIZE: usize = 100;
+const FLUSH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// 🧊 [冷数据归档] 当前写入文件超过这个大小就轮转归档并用 xz 压缩
+const LOG_ROTATE_BYTES: u64 = 8 * 1024 * 1024;
+const LOG_DIR_DEFAULT: &str = "audit_logs";
+const CURRENT_LOG_NAME: &str = "current.ndjson";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub ts: u64,
+    pub session_id: String,
+    pub model: String,
+    pub simplified_model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    pub currency: String,
+    pub stream: bool,
+    pub fused: bool,
+    pub upstream_latency_ms: f64,
+    pub upstream_status: u16,
+}
+
+pub struct AuditSink {
+    tx: mpsc::Sender<AuditRecord>,
+}
+
+impl AuditSink {
+    /// 从环境变量装配：`AUDIT_SINK_URL`（ES `_bulk` 端点，例如
+    /// `http://localhost:9200/_bulk`）和 `AUDIT_SINK_INDEX`（默认 `deepsentine-audit`）。
+    /// 未设置 URL 时仍然启动后台任务，只是它只消费不发送，调用方无需关心开关状态。
+    pub fn from_env() -> Self {
+        let sink_url = std::env::var("AUDIT_SINK_URL").ok().filter(|s| !s.is_empty());
+        let index = std::env::var("AUDIT_SINK_INDEX").unwrap_or_else(|_| "deepsentine-audit".to_string());
+
+        let log_dir = log_dir_from_env();
+        if let Err(e) = std::fs::create_dir_all(&log_dir) {
+            println!("⚠️ [审计] 创建本地审计日志目录失败: {}", e);
+        }
+        let current_path = log_dir.join(CURRENT_LOG_NAME);
+        let mut current_file = open_current_log(&current_path);
+        let mut current_size = current_file
+            .as_ref()
+            .and_then(|f| f.metadata().ok())
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let (tx, mut rx) = mpsc::channel::<AuditRecord>(CHANNEL_CAPACITY);
+
+        tokio::spawn(async move {
+            let http = reqwest::Client::new();
+            let mut buffer: Vec<AuditRecord> = Vec::with_capacity(FLUSH_BATCH_SIZE);
+            let mut ticker = tokio::time::interval(FLUSH_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    maybe_record = rx.recv() => {
+                        match maybe_record {
+                            Some(record) => {
+                                // 🧾 [追加式日志] 先落盘到本地归档日志，再攒批推 ES；两条链路互不影响
+                                append_to_log(&log_dir, &current_path, &mut current_file, &mut current_size, &record);
+                                buffer.push(record);
+                                if buffer.len() >= FLUSH_BATCH_SIZE {
+                                    flush(&http, sink_url.as_deref(), &index, &mut buffer).await;
+                                }
+                            }
+                            None => break, // 发送端全部 drop，优雅退出
+                        }
+                    }
+                    _ = ticker.tick() => {
+                        if !buffer.is_empty() {
+                            flush(&http, sink_url.as_deref(), &index, &mut buffer).await;
+                        }
+                    }
+                }
+            }
+
+            // 退出前做最后一次 flush，尽量不丢尾部数据
+            if !buffer.is_empty() {
+                flush(&http, sink_url.as_deref(), &index, &mut buffer).await;
+            }
+        });
+
+        AuditSink { tx }
+    }
+
+    /// 记录一条审计事件；channel 满了就直接丢弃（宁可丢审计日志，不能拖慢计费主路径）
+    pub fn record(&self, record: AuditRecord) {
+        if let Err(e) = self.tx.try_send(record) {
+            println!("⚠️ [审计] 审计队列已满，丢弃一条记录: {}", e);
+        }
+    }
+}
+
+/// 把缓冲区打成 ES `_bulk` 要求的 NDJSON（每条记录前加一行 `{"index":{...}}`）并 POST 出去
+async fn flush(http: &reqwest::Client, sink_url: Option<&str>, index: &str, buffer: &mut Vec<AuditRecord>) {
+    let Some(url) = sink_url else {
+        buffer.clear();
+        return;
+    };
+
+    let mut body = String::new();
+    for record in buffer.iter() {
+        body.push_str(&json!({"index": {"_index": index}}).to_string());
+        body.push('\n');
+        body.push_str(&serde_json::to_string(record).unwrap_or_default());
+        body.push('\n');
+    }
+
+    match http
+        .post(url)
+        .header("Content-Type", "application/x-ndjson")
+        .body(body)
+        .send()
+        .await
+    {
+        Ok(resp) if !resp.status().is_success() => {
+            println!("⚠️ [审计] 写入审计日志失败，状态码: {}", resp.status());
+        }
+        Err(e) => println!("⚠️ [审计] 写入审计日志失败: {}", e),
+        _ => {}
+    }
+
+    buffer.clear();
+}
+
+fn log_dir_from_env() -> PathBuf {
+    PathBuf::from(std::env::var("AUDIT_LOG_DIR").unwrap_or_else(|_| LOG_DIR_DEFAULT.to_string()))
+}
+
+fn open_current_log(path: &Path) -> Option<std::fs::File> {
+    match std::fs::OpenOptions::new().create(true).append(true).open(path) {
+        Ok(f) => Some(f),
+        Err(e) => {
+            println!("⚠️ [审计] 打开本地审计日志失败: {}", e);
+            None
+        }
+    }
+}
+
+/// 追加一行 NDJSON 到当前日志文件；超过 [`LOG_ROTATE_BYTES`] 就轮转归档并重新打开一个空文件
+fn append_to_log(dir: &Path, current_path: &Path, file: &mut Option<std::fs::File>, size: &mut u64, record: &AuditRecord) {
+    let Some(f) = file.as_mut() else { return };
+    let line = match serde_json::to_string(record) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("⚠️ [审计] 序列化审计记录失败: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = writeln!(f, "{}", line) {
+        println!("⚠️ [审计] 写入本地审计日志失败: {}", e);
+        return;
+    }
+    *size += line.len() as u64 + 1;
+
+    if *size >= LOG_ROTATE_BYTES {
+        if let Err(e) = rotate_log(dir, current_path) {
+            println!("⚠️ [审计] 归档本地审计日志失败: {}", e);
+        }
+        *file = open_current_log(current_path);
+        *size = 0;
+    }
+}
+
+/// 🧊 [冷数据归档] 把写满的当前日志改名成带时间戳的归档文件，用 xz 压缩后删除明文副本
+fn rotate_log(dir: &Path, current_path: &Path) -> std::io::Result<()> {
+    let ts = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let archived_path = dir.join(format!("audit-{}.ndjson", ts));
+    std::fs::rename(current_path, &archived_path)?;
+
+    let mut input = std::fs::File::open(&archived_path)?;
+    let compressed_path = dir.join(format!("audit-{}.ndjson.xz", ts));
+    let output = std::fs::File::create(&compressed_path)?;
+    let mut encoder = XzEncoder::new(output, 6);
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    drop(input);
+    std::fs::remove_file(&archived_path)?;
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct BucketAcc {
+    total_cost: f64,
+    request_count: u64,
+    tokens_by_model: HashMap<String, (u64, u64)>,
+}
+
+/// `GET /analytics?from=&to=&bucket=` 的落地点：扫描本地审计日志目录（含已压缩归档的冷分段），
+/// 按 `[from, to)` 过滤后以 `bucket_secs` 为粒度聚合出每个时间桶的总花费 / 请求数 / 各模型 token 数
+pub async fn query_analytics(from: u64, to: u64, bucket_secs: u64) -> Vec<Value> {
+    tokio::task::spawn_blocking(move || query_analytics_blocking(from, to, bucket_secs.max(1)))
+        .await
+        .unwrap_or_default()
+}
+
+fn query_analytics_blocking(from: u64, to: u64, bucket_secs: u64) -> Vec<Value> {
+    let dir = log_dir_from_env();
+    let mut buckets: BTreeMap<u64, BucketAcc> = BTreeMap::new();
+
+    let Ok(entries) = std::fs::read_dir(&dir) else { return vec![] };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+
+        // 归档分段可能是明文 `.ndjson`（刚轮转、还没压缩完）或压缩后的 `.ndjson.xz`；
+        // 解析时对两者透明处理，调用方不需要关心某一段是否已经冷归档
+        let content = if name.ends_with(".ndjson.xz") {
+            read_xz_to_string(&path)
+        } else if name == CURRENT_LOG_NAME || name.ends_with(".ndjson") {
+            std::fs::read_to_string(&path).ok()
+        } else {
+            continue;
+        };
+        let Some(content) = content else { continue };
+
+        for line in content.lines() {
+            let Ok(record) = serde_json::from_str::<AuditRecord>(line) else { continue };
+            if record.ts < from || record.ts >= to {
+                continue;
+            }
+            let bucket_start = record.ts - (record.ts % bucket_secs);
+            let acc = buckets.entry(bucket_start).or_default();
+            acc.total_cost += record.cost;
+            acc.request_count += 1;
+            let stat = acc.tokens_by_model.entry(record.model.clone()).or_insert((0, 0));
+            stat.0 += record.prompt_tokens;
+            stat.1 += record.completion_tokens;
+        }
+    }
+
+    buckets
+        .into_iter()
+        .map(|(bucket_start, acc)| {
+            let tokens_by_model: serde_json::Map<String, Value> = acc
+                .tokens_by_model
+                .iter()
+                .map(|(model, (prompt, completion))| {
+                    (model.clone(), json!({ "prompt_tokens": prompt, "completion_tokens": completion }))
+                })
+                .collect();
+            json!({
+                "bucket_start": bucket_start,
+                "total_cost": acc.total_cost,
+                "request_count": acc.request_count,
+                "tokens_by_model": tokens_by_model,
+            })
+        })
+        .collect()
+}
+
+fn read_xz_to_string(path: &Path) -> Option<String> {
+    let file = std::fs::File::open(path).ok()?;
+    let mut decoder = XzDecoder::new(file);
+    let mut content = String::new();
+    decoder.read_to_string(&mut content).ok()?;
+    Some(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // `query_analytics_blocking` 读 `AUDIT_LOG_DIR` 这个进程级环境变量，测试之间得互斥，
+    // 不然并发跑的用例会互相踩到对方设置的目录
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_temp_log_dir<F: FnOnce(&Path)>(f: F) {
+        let _guard = ENV_LOCK.lock().unwrap();
+        let dir = std::env::temp_dir().join(format!("deepsentine-audit-test-{}-{:?}", std::process::id(), std::thread::current().id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_var("AUDIT_LOG_DIR", &dir);
+
+        f(&dir);
+
+        std::env::remove_var("AUDIT_LOG_DIR");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn sample_record(ts: u64, model: &str, cost: f64) -> AuditRecord {
+        AuditRecord {
+            ts,
+            session_id: "s1".to_string(),
+            model: model.to_string(),
+            simplified_model: model.to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 20,
+            cost,
+            currency: "USD".to_string(),
+            stream: false,
+            fused: false,
+            upstream_latency_ms: 1.0,
+            upstream_status: 200,
+        }
+    }
+
+    #[test]
+    fn query_analytics_aggregates_records_within_the_requested_window() {
+        with_temp_log_dir(|dir| {
+            let lines: String = [sample_record(100, "m1", 1.5), sample_record(105, "m1", 2.5), sample_record(9999, "m1", 100.0)]
+                .iter()
+                .map(|r| serde_json::to_string(r).unwrap() + "\n")
+                .collect();
+            std::fs::write(dir.join(CURRENT_LOG_NAME), lines).unwrap();
+
+            let buckets = query_analytics_blocking(0, 1000, 60);
+            assert_eq!(buckets.len(), 1);
+            let bucket = &buckets[0];
+            assert_eq!(bucket["request_count"], 2);
+            assert!((bucket["total_cost"].as_f64().unwrap() - 4.0).abs() < 1e-9);
+            assert_eq!(bucket["tokens_by_model"]["m1"]["prompt_tokens"], 20);
+        });
+    }
+
+    #[test]
+    fn query_analytics_excludes_records_outside_from_to() {
+        with_temp_log_dir(|dir| {
+            let lines = serde_json::to_string(&sample_record(5000, "m1", 1.0)).unwrap() + "\n";
+            std::fs::write(dir.join(CURRENT_LOG_NAME), lines).unwrap();
+
+            let buckets = query_analytics_blocking(0, 1000, 60);
+            assert!(buckets.is_empty());
+        });
+    }
+
+    #[test]
+    fn query_analytics_reads_compressed_archive_segments_transparently() {
+        with_temp_log_dir(|dir| {
+            let line = serde_json::to_string(&sample_record(42, "m2", 3.0)).unwrap() + "\n";
+            let output = std::fs::File::create(dir.join("audit-1.ndjson.xz")).unwrap();
+            let mut encoder = XzEncoder::new(output, 6);
+            encoder.write_all(line.as_bytes()).unwrap();
+            encoder.finish().unwrap();
+
+            let buckets = query_analytics_blocking(0, 1000, 60);
+            assert_eq!(buckets.len(), 1);
+            assert_eq!(buckets[0]["request_count"], 1);
+        });
+    }
+}
@@ -0,0 +1,189 @@
+// 📡 [跨实例计费总线] 可选的 NATS/JetStream 后端
+//
+// 之前计费事件只通过进程内 `tokio::sync::broadcast`（`ws_tx`）分发，水平扩展多个
+// DeepSentine 实例后，各实例的累计花费互相看不见，灵动岛也只能连一个实例。
+// 这里加一条可选总线：
+//   1. 每次扣费时把 `{client_id, delta_cost, instance_id}` 发布到 `deepsentine.billing`，
+//      其它实例订阅同一个 subject，把非本实例产生的增量累加进本地账本，这样熔断判断用的
+//      就是跨实例的真实总花费，而不是单实例视角。
+//   2. JetStream 把面向前端展示的 billing 消息落盘成持久化流，新连接的仪表盘可以用
+//      `GET /billing/replay?since=<unix_secs>` 补读断线期间错过的历史事件。
+// 没有配置 `NATS_URL` 时，整条链路退化为纯单实例模式（发布变成 no-op，重放永远返回空列表），
+// 行为与引入 NATS 之前完全一致。
+
+use async_nats::jetstream;
+use serde_json::Value;
+
+pub const BILLING_SUBJECT: &str = "deepsentine.billing";
+const BILLING_STREAM: &str = "DEEPSENTINE_BILLING";
+const REPLAY_BATCH_LIMIT: usize = 1000;
+
+pub struct BillingBus {
+    // 本实例的唯一标识，用于在消费 NATS 消息时跳过"自己发布给自己"的消息，避免重复计费
+    pub instance_id: String,
+    jetstream: Option<jetstream::Context>,
+}
+
+impl BillingBus {
+    /// 从 `NATS_URL` 装配；未设置或连接失败时退化为空转总线
+    pub async fn from_env() -> Self {
+        let instance_id = format!(
+            "{}-{:x}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos())
+                .unwrap_or(0)
+        );
+
+        let Some(url) = std::env::var("NATS_URL").ok().filter(|s| !s.is_empty()) else {
+            println!("ℹ️ [计费总线] 未配置 NATS_URL，退化为单实例模式（仅本地 broadcast）");
+            return BillingBus { instance_id, jetstream: None };
+        };
+
+        match async_nats::connect(&url).await {
+            Ok(client) => {
+                let js = jetstream::new(client);
+                if let Err(e) = js
+                    .get_or_create_stream(jetstream::stream::Config {
+                        name: BILLING_STREAM.to_string(),
+                        subjects: vec![BILLING_SUBJECT.to_string()],
+                        ..Default::default()
+                    })
+                    .await
+                {
+                    println!("⚠️ [计费总线] 创建/获取 JetStream 流失败: {}", e);
+                }
+                println!("✅ [计费总线] 已连接 NATS，实例标识: {}", instance_id);
+                BillingBus { instance_id, jetstream: Some(js) }
+            }
+            Err(e) => {
+                println!("⚠️ [计费总线] 连接 NATS 失败（{}），退化为单实例模式", e);
+                BillingBus { instance_id, jetstream: None }
+            }
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.jetstream.is_some()
+    }
+
+    /// 发布一条事件到 JetStream；没有配置 NATS 时直接忽略
+    pub async fn publish(&self, event: &Value) {
+        let Some(js) = &self.jetstream else { return };
+        let payload = match serde_json::to_vec(event) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("⚠️ [计费总线] 序列化事件失败: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = js.publish(BILLING_SUBJECT, payload.into()).await {
+            println!("⚠️ [计费总线] 发布到 NATS 失败: {}", e);
+        }
+    }
+
+    /// 订阅 subject 的普通（非 JetStream ack）消费者，供跨实例账本聚合 / WS 桥接使用
+    pub async fn subscribe(&self) -> Option<async_nats::Subscriber> {
+        let js = self.jetstream.as_ref()?;
+        match js.client().subscribe(BILLING_SUBJECT).await {
+            Ok(sub) => Some(sub),
+            Err(e) => {
+                println!("⚠️ [计费总线] 订阅 NATS 失败: {}", e);
+                None
+            }
+        }
+    }
+
+    /// `GET /billing/replay?since=<unix_secs>`：从 JetStream 流里按起始时间重放历史事件
+    pub async fn replay_since(&self, since_unix_secs: u64) -> Vec<Value> {
+        let Some(js) = &self.jetstream else { return vec![] };
+
+        let start_time = time::OffsetDateTime::from_unix_timestamp(since_unix_secs as i64)
+            .unwrap_or(time::OffsetDateTime::UNIX_EPOCH);
+
+        let stream = match js.get_stream(BILLING_STREAM).await {
+            Ok(s) => s,
+            Err(e) => {
+                println!("⚠️ [计费总线] 获取 JetStream 流失败: {}", e);
+                return vec![];
+            }
+        };
+
+        let consumer = match stream
+            .create_consumer(jetstream::consumer::pull::Config {
+                deliver_policy: jetstream::consumer::DeliverPolicy::ByStartTime { start_time },
+                ack_policy: jetstream::consumer::AckPolicy::None,
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                println!("⚠️ [计费总线] 创建重放 consumer 失败: {}", e);
+                return vec![];
+            }
+        };
+
+        let messages = match consumer
+            .fetch()
+            .max_messages(REPLAY_BATCH_LIMIT)
+            .expires(std::time::Duration::from_secs(2))
+            .messages()
+            .await
+        {
+            Ok(m) => m,
+            Err(e) => {
+                println!("⚠️ [计费总线] 拉取重放消息失败: {}", e);
+                return vec![];
+            }
+        };
+
+        let mut events = Vec::new();
+        let mut messages = std::pin::pin!(messages);
+        use futures_util::StreamExt;
+        while let Some(Ok(msg)) = messages.next().await {
+            if let Ok(value) = serde_json::from_slice::<Value>(&msg.payload) {
+                events.push(value);
+            }
+        }
+
+        println!("🔄 [计费总线] 重放了 {} 条自 {} 起的历史计费事件", events.len(), since_unix_secs);
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 不走 `from_env`（要联网连 NATS），直接构造一个退化成单实例模式的总线
+    fn disabled_bus() -> BillingBus {
+        BillingBus { instance_id: "test-instance".to_string(), jetstream: None }
+    }
+
+    #[test]
+    fn disabled_bus_reports_not_enabled() {
+        assert!(!disabled_bus().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn publish_is_a_noop_without_a_configured_backend() {
+        let bus = disabled_bus();
+        // 没有 jetstream 时 publish 必须静默忽略，不能 panic 也不能阻塞
+        bus.publish(&serde_json::json!({"client_id": "c1", "delta_cost": 1.0})).await;
+    }
+
+    #[tokio::test]
+    async fn replay_since_returns_empty_without_a_configured_backend() {
+        let bus = disabled_bus();
+        let events = bus.replay_since(0).await;
+        assert!(events.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_returns_none_without_a_configured_backend() {
+        let bus = disabled_bus();
+        assert!(bus.subscribe().await.is_none());
+    }
+}
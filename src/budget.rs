@@ -0,0 +1,404 @@
+// 💰 [多租户熔断] 按 API key / session_id 维度的独立预算与熔断状态
+//
+// 在此之前，哨兵只有一个全局 `total_cost` / `budget_limit`，所有调用方共享同一条
+// 熔断线。这里把账本拆成按租户（`tenant_id`）维度的 `TenantBudget`，
+// `DashMap` 提供无锁的并发读写，`AppState` 仍然保留一个全局汇总用于 `/status` 的
+// `global_total_cost`、烧钱速率采样和停机落盘。
+
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 🛡️ [真熔断] Open 状态下拒绝一切请求的冷却时间：超过这个时长后转入 HalfOpen 放行一个探测请求
+const CIRCUIT_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// 🛡️ [真熔断] 三态熔断器：Closed 正常放行；Open 一律拒绝；HalfOpen 只放行一个探测请求
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+/// `admit()` 的裁决结果：调用方据此决定放行 / 拒绝，以及是否要提示"这是一次探测请求"
+pub enum CircuitDecision {
+    Admit,
+    AdmitProbe,
+    Reject { retry_after_secs: u64 },
+}
+
+/// 单个租户的计费状态：累计花费（定点放大 1e12，和旧的 `total_cost` 单位一致）、
+/// 独立限额，以及三态熔断器状态。
+pub struct TenantBudget {
+    pub spend_fixed: AtomicU64,
+    pub limit: Mutex<f64>,
+    // 🛡️ [真熔断] 保留 `fused` 作为熔断器处于 Open 态的粗粒度标志，供旧的只读展示逻辑使用
+    pub fused: AtomicBool,
+    circuit: Mutex<CircuitState>,
+    opened_at: Mutex<Option<Instant>>,
+}
+
+impl TenantBudget {
+    fn new(default_limit: f64) -> Self {
+        TenantBudget {
+            spend_fixed: AtomicU64::new(0),
+            limit: Mutex::new(default_limit),
+            fused: AtomicBool::new(false),
+            circuit: Mutex::new(CircuitState::Closed),
+            opened_at: Mutex::new(None),
+        }
+    }
+
+    pub fn spend(&self) -> f64 {
+        self.spend_fixed.load(Ordering::Relaxed) as f64 / 1_000_000_000_000.0
+    }
+
+    pub fn limit(&self) -> f64 {
+        *self.limit.lock().unwrap()
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.spend() >= self.limit()
+    }
+
+    pub fn add_cost(&self, cost: f64) {
+        self.spend_fixed
+            .fetch_add((cost * 1_000_000_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        *self.circuit.lock().unwrap()
+    }
+
+    /// 🛡️ [真熔断] 在转发请求之前调用：Closed 态下一旦发现超限就立即打开熔断器并拒绝本次请求；
+    /// Open 态下冷却时间到了就转入 HalfOpen 放行一个探测请求，否则继续拒绝；HalfOpen 态下
+    /// 已经有一个探测请求在路上，新请求一律拒绝直到探测结果落地。
+    /// 返回 `(裁决, 这次调用是否让熔断器发生了状态迁移)`，后者用于决定是否要广播 `circuit_break`。
+    pub fn admit(&self) -> (CircuitDecision, bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+        let previous = *circuit;
+
+        let (decision, next) = match previous {
+            CircuitState::Closed => {
+                if self.over_budget() {
+                    (CircuitDecision::Reject { retry_after_secs: CIRCUIT_COOLDOWN.as_secs() }, CircuitState::Open)
+                } else {
+                    (CircuitDecision::Admit, CircuitState::Closed)
+                }
+            }
+            CircuitState::Open => {
+                let elapsed = self
+                    .opened_at
+                    .lock()
+                    .unwrap()
+                    .map(|t| t.elapsed())
+                    .unwrap_or(Duration::ZERO);
+                if elapsed >= CIRCUIT_COOLDOWN {
+                    (CircuitDecision::AdmitProbe, CircuitState::HalfOpen)
+                } else {
+                    let retry_after_secs = CIRCUIT_COOLDOWN.saturating_sub(elapsed).as_secs().max(1);
+                    (CircuitDecision::Reject { retry_after_secs }, CircuitState::Open)
+                }
+            }
+            CircuitState::HalfOpen => (CircuitDecision::Reject { retry_after_secs: 1 }, CircuitState::HalfOpen),
+        };
+
+        let transitioned = next != previous;
+        if transitioned {
+            *circuit = next;
+            if next == CircuitState::Open {
+                *self.opened_at.lock().unwrap() = Some(Instant::now());
+                self.fused.store(true, Ordering::SeqCst);
+            }
+        }
+
+        (decision, transitioned)
+    }
+
+    /// 🛡️ [真熔断] 流式响应过程中实时发现超限时调用，不经过 `admit()` 的 Closed 分支也能直接打开熔断器。
+    /// 返回是否是这次调用让熔断器发生了迁移（用于避免重复广播）。
+    pub fn trip(&self) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        if *circuit == CircuitState::Open {
+            return false;
+        }
+        *circuit = CircuitState::Open;
+        *self.opened_at.lock().unwrap() = Some(Instant::now());
+        self.fused.store(true, Ordering::SeqCst);
+        true
+    }
+
+    /// 🛡️ [真熔断] HalfOpen 探测请求结束后调用：成功且已经回到预算线以下就闭合熔断器，
+    /// 否则重新打开冷却窗口。返回是否发生了状态迁移。
+    pub fn record_probe_result(&self, success: bool) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        if *circuit != CircuitState::HalfOpen {
+            return false;
+        }
+
+        if success && !self.over_budget() {
+            *circuit = CircuitState::Closed;
+            self.fused.store(false, Ordering::SeqCst);
+            *self.opened_at.lock().unwrap() = None;
+        } else {
+            *circuit = CircuitState::Open;
+            *self.opened_at.lock().unwrap() = Some(Instant::now());
+        }
+        true
+    }
+
+    pub fn reset(&self) {
+        self.spend_fixed.store(0, Ordering::Relaxed);
+        self.fused.store(false, Ordering::Relaxed);
+        *self.circuit.lock().unwrap() = CircuitState::Closed;
+        *self.opened_at.lock().unwrap() = None;
+    }
+}
+
+/// 所有租户账本的集合，外加一个全局汇总（供 `/status`、烧钱速率采样和停机落盘使用）
+pub struct TenantLedger {
+    tenants: DashMap<String, std::sync::Arc<TenantBudget>>,
+    pub default_limit: Mutex<f64>,
+    pub global_spend_fixed: AtomicU64,
+}
+
+impl TenantLedger {
+    pub fn new(default_limit: f64) -> Self {
+        TenantLedger {
+            tenants: DashMap::new(),
+            default_limit: Mutex::new(default_limit),
+            global_spend_fixed: AtomicU64::new(0),
+        }
+    }
+
+    /// 取得（或创建）指定租户的账本
+    pub fn get_or_create(&self, tenant_id: &str) -> std::sync::Arc<TenantBudget> {
+        if let Some(existing) = self.tenants.get(tenant_id) {
+            return existing.clone();
+        }
+        let default_limit = *self.default_limit.lock().unwrap();
+        self.tenants
+            .entry(tenant_id.to_string())
+            .or_insert_with(|| std::sync::Arc::new(TenantBudget::new(default_limit)))
+            .clone()
+    }
+
+    /// 计费：同时写入租户账本与全局汇总
+    pub fn record_cost(&self, tenant_id: &str, cost: f64) {
+        self.get_or_create(tenant_id).add_cost(cost);
+        self.global_spend_fixed
+            .fetch_add((cost * 1_000_000_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    pub fn global_spend(&self) -> f64 {
+        self.global_spend_fixed.load(Ordering::Relaxed) as f64 / 1_000_000_000_000.0
+    }
+
+    pub fn reset_global(&self) {
+        self.global_spend_fixed.store(0, Ordering::Relaxed);
+    }
+
+    /// 列出所有已知租户及其花费/限额，供 `GET /clients` 一类的管理端点使用
+    pub fn snapshot(&self) -> Vec<(String, f64, f64)> {
+        self.tenants
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().spend(), entry.value().limit()))
+            .collect()
+    }
+}
+
+/// 从 `Authorization: Bearer <key>` 头里提取调用方标识；拿不到 API key 时，按
+/// `X-Forwarded-For` / `Forwarded` 头跳过 `TRUSTED_PROXY_COUNT` 个可信代理追加的条目，取出客户端 IP 作为调用方标识；
+/// 两者都没有才回退到 `session_id`。
+/// 这让同一个 API key（或同一来源 IP）下的多个 session 共享同一条预算线，完全匿名的调用仍按 session 隔离。
+pub fn resolve_tenant_id(headers: &axum::http::HeaderMap, session_id: &str) -> String {
+    if let Some(api_key) = headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim_start_matches("Bearer ").trim().to_string())
+        .filter(|v| !v.is_empty())
+    {
+        return api_key;
+    }
+
+    if let Some(ip) = parse_client_ip(headers) {
+        return format!("ip:{}", ip);
+    }
+
+    format!("session:{}", session_id)
+}
+
+/// 🐛 [修复] 本服务前面信任的反向代理跳数，由 `TRUSTED_PROXY_COUNT` 配置（缺省 0）。
+/// `X-Forwarded-For`/`Forwarded` 里最右侧的 N 条是这些跳数各自往链上追加的条目——只有它们
+/// 是可信的；再往左的一切（包括原来直接取的"最左侧"）都可能是外部调用方自己伪造的头，
+/// 不能直接拿来当计费/熔断标识，否则谁都能发 `X-Forwarded-For: 受害者IP` 把花费记到别人账上。
+fn trusted_proxy_count() -> usize {
+    std::env::var("TRUSTED_PROXY_COUNT").ok().and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+/// 从一串以逗号分隔的地址链（最左侧是最初的客户端，越往右越接近本服务）里，跳过最右侧
+/// `trusted_proxy_count` 个可信代理追加的条目，取其左边紧邻的那一个作为真实客户端 IP；
+/// 链长不足以覆盖配置的可信跳数时，只能认为整条链都是可信基础设施追加的，退化为取最左侧
+fn pick_trusted_entry<'a>(entries: &[&'a str]) -> Option<&'a str> {
+    if entries.is_empty() {
+        return None;
+    }
+    let trusted = trusted_proxy_count();
+    let idx = entries.len().saturating_sub(trusted + 1);
+    Some(entries[idx])
+}
+
+/// 按优先级依次尝试 `X-Forwarded-For` 和标准 `Forwarded` 头，按可信代理跳数取真实客户端地址
+fn parse_client_ip(headers: &axum::http::HeaderMap) -> Option<String> {
+    if let Some(xff) = headers.get("X-Forwarded-For").and_then(|v| v.to_str().ok()) {
+        let entries: Vec<&str> = xff.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).collect();
+        if let Some(ip) = pick_trusted_entry(&entries) {
+            return Some(ip.to_string());
+        }
+    }
+
+    if let Some(forwarded) = headers.get("Forwarded").and_then(|v| v.to_str().ok()) {
+        // RFC 7239: `Forwarded: for=1.2.3.4, for=5.6.7.8;proto=https`，按出现顺序收集所有 `for=` 的值
+        let mut entries: Vec<String> = Vec::new();
+        for directive in forwarded.split(',') {
+            for part in directive.split(';') {
+                let part = part.trim();
+                if let Some(value) = part.strip_prefix("for=") {
+                    let cleaned = value.trim_matches('"');
+                    // IPv6 地址在 Forwarded 头里会被方括号包裹，如 for="[2001:db8::1]"
+                    let cleaned = cleaned.trim_start_matches('[').trim_end_matches(']');
+                    if !cleaned.is_empty() {
+                        entries.push(cleaned.to_string());
+                    }
+                }
+            }
+        }
+        let refs: Vec<&str> = entries.iter().map(|s| s.as_str()).collect();
+        if let Some(ip) = pick_trusted_entry(&refs) {
+            return Some(ip.to_string());
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 🛡️ [回归] chunk1-1: Closed 态下未超预算应该放行，且不发生状态迁移
+    #[test]
+    fn closed_admits_when_under_budget() {
+        let budget = TenantBudget::new(10.0);
+        budget.add_cost(1.0);
+
+        let (decision, transitioned) = budget.admit();
+
+        assert!(matches!(decision, CircuitDecision::Admit));
+        assert!(!transitioned);
+        assert_eq!(budget.circuit_state(), CircuitState::Closed);
+    }
+
+    /// 🛡️ [回归] chunk1-1: Closed 态一旦超预算，应立即拒绝本次请求并打开熔断器
+    #[test]
+    fn closed_trips_open_when_over_budget() {
+        let budget = TenantBudget::new(10.0);
+        budget.add_cost(20.0);
+
+        let (decision, transitioned) = budget.admit();
+
+        assert!(matches!(decision, CircuitDecision::Reject { .. }));
+        assert!(transitioned);
+        assert_eq!(budget.circuit_state(), CircuitState::Open);
+        assert!(budget.fused.load(Ordering::SeqCst));
+    }
+
+    /// 🛡️ [回归] chunk1-1: Open 态冷却时间未到之前，后续请求应继续被拒绝，且不再重复迁移状态
+    #[test]
+    fn open_keeps_rejecting_before_cooldown_elapses() {
+        let budget = TenantBudget::new(10.0);
+        budget.add_cost(20.0);
+        budget.admit(); // Closed -> Open
+
+        let (decision, transitioned) = budget.admit();
+
+        assert!(matches!(decision, CircuitDecision::Reject { .. }));
+        assert!(!transitioned);
+        assert_eq!(budget.circuit_state(), CircuitState::Open);
+    }
+
+    /// 🛡️ [回归] chunk1-1: `trip()` 在熔断器已经 Open 时应该是幂等的，只有真正触发迁移的那次调用返回 true
+    #[test]
+    fn trip_is_idempotent() {
+        let budget = TenantBudget::new(10.0);
+
+        assert!(budget.trip());
+        assert!(!budget.trip());
+        assert_eq!(budget.circuit_state(), CircuitState::Open);
+    }
+
+    /// 🛡️ [回归] chunk1-1: HalfOpen 探测成功且已回到预算线以下时应该闭合熔断器
+    #[test]
+    fn half_open_probe_success_closes_circuit() {
+        let budget = TenantBudget::new(10.0);
+        budget.trip(); // Closed -> Open
+        // 手动把状态搬到 HalfOpen（生产代码里由 admit() 在冷却到期后完成，这里直接构造待测前置状态）
+        *budget.circuit.lock().unwrap() = CircuitState::HalfOpen;
+
+        let transitioned = budget.record_probe_result(true);
+
+        assert!(transitioned);
+        assert_eq!(budget.circuit_state(), CircuitState::Closed);
+        assert!(!budget.fused.load(Ordering::SeqCst));
+    }
+
+    /// 🛡️ [回归] chunk1-1: HalfOpen 探测失败（或仍超预算）应该重新打开熔断器
+    #[test]
+    fn half_open_probe_failure_reopens_circuit() {
+        let budget = TenantBudget::new(10.0);
+        budget.add_cost(20.0);
+        budget.trip();
+        *budget.circuit.lock().unwrap() = CircuitState::HalfOpen;
+
+        let transitioned = budget.record_probe_result(false);
+
+        assert!(transitioned);
+        assert_eq!(budget.circuit_state(), CircuitState::Open);
+    }
+
+    /// 🛡️ [回归] chunk1-1: 不在 HalfOpen 态时调用 `record_probe_result` 应该是无操作
+    #[test]
+    fn record_probe_result_noop_when_not_half_open() {
+        let budget = TenantBudget::new(10.0);
+
+        let transitioned = budget.record_probe_result(true);
+
+        assert!(!transitioned);
+        assert_eq!(budget.circuit_state(), CircuitState::Closed);
+    }
+
+    /// 🛡️ [回归] chunk1-1: `reset()` 应该把熔断器、花费和 fused 标志都清回初始状态
+    #[test]
+    fn reset_clears_circuit_and_spend() {
+        let budget = TenantBudget::new(10.0);
+        budget.add_cost(20.0);
+        budget.trip();
+
+        budget.reset();
+
+        assert_eq!(budget.circuit_state(), CircuitState::Closed);
+        assert!(!budget.fused.load(Ordering::SeqCst));
+        assert_eq!(budget.spend(), 0.0);
+    }
+
+    /// 🔒 [回归] chunk1-3: 没有配置可信代理时（默认 `TRUSTED_PROXY_COUNT=0`），取 XFF 链最右侧
+    /// 一跳，而不是可被外部调用方随意伪造的最左侧一跳
+    #[test]
+    fn parse_client_ip_prefers_rightmost_hop_by_default() {
+        let mut headers = axum::http::HeaderMap::new();
+        headers.insert("X-Forwarded-For", "victim-ip, real-proxy-observed-ip".parse().unwrap());
+
+        assert_eq!(parse_client_ip(&headers), Some("real-proxy-observed-ip".to_string()));
+    }
+}
@@ -0,0 +1,157 @@
+// 📊 [烧钱速率预测] 在 `alerts.rs` 的 EWMA 趋势线之外，再提供一条更直接的"还剩多久爆预算"预测
+//
+// `alerts.rs` 的 `spend_rate_spike` 回答的是"现在烧得是不是比平时快"；这里回答的是更直接的问题——
+// 按最近这段时间的瞬时速率外推，还有多久会撞到预算线。维护一个固定容量的环形缓冲区，周期性
+// 采样 `(Instant, total_cost)`，用窗口内最早/最新两个样本算出瞬时速率，再结合 `budget_limit`
+// 算出预计耗尽时间 `eta`。`eta` 小于阈值时广播 `burn_rate_alarm`，每个采样周期都广播一次
+// `cost_rate` 供灵动岛画实时曲线。
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 采样周期：每隔这么久读一次全局累计成本
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// 环形缓冲区容量：结合采样周期，约覆盖最近 15 分钟的窗口
+const WINDOW_CAPACITY: usize = 60;
+
+/// 预计耗尽时间低于这个阈值才报警，避免正常烧钱速度下也天天告警
+const ALARM_ETA_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+
+/// 同一次告警的最短重复间隔
+const ALARM_DEBOUNCE: Duration = Duration::from_secs(60);
+
+struct Sample {
+    at: Instant,
+    cost: f64,
+}
+
+/// 一次采样的结论：瞬时速率（元/秒）与按此速率推算的预计耗尽秒数（速率 <= 0 视为无穷，返回 `None`）
+pub struct BurnRateReading {
+    pub rate_per_sec: f64,
+    pub eta_seconds: Option<f64>,
+}
+
+pub struct BurnRateTracker {
+    window: Mutex<VecDeque<Sample>>,
+    last_alarm_at: Mutex<Option<Instant>>,
+}
+
+impl BurnRateTracker {
+    pub fn new() -> Self {
+        BurnRateTracker {
+            window: Mutex::new(VecDeque::with_capacity(WINDOW_CAPACITY)),
+            last_alarm_at: Mutex::new(None),
+        }
+    }
+
+    /// `reset_cost`（全局重置）时调用：成本归零后旧样本会造成一次虚假的负速率跳变，直接清空窗口
+    pub fn reset(&self) {
+        self.window.lock().unwrap().clear();
+        *self.last_alarm_at.lock().unwrap() = None;
+    }
+
+    /// 喂入最新的全局累计成本，返回本次窗口算出的瞬时速率与预计耗尽时间
+    pub fn sample(&self, current_cost: f64, budget_limit: f64) -> BurnRateReading {
+        let now = Instant::now();
+        let mut window = self.window.lock().unwrap();
+        window.push_back(Sample { at: now, cost: current_cost });
+        while window.len() > WINDOW_CAPACITY {
+            window.pop_front();
+        }
+
+        let rate_per_sec = match (window.front(), window.back()) {
+            (Some(first), Some(last)) if last.at > first.at => {
+                (last.cost - first.cost) / last.at.duration_since(first.at).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        drop(window);
+
+        let eta_seconds = if rate_per_sec > 0.0 {
+            Some(((budget_limit - current_cost).max(0.0)) / rate_per_sec)
+        } else {
+            None
+        };
+
+        BurnRateReading { rate_per_sec, eta_seconds }
+    }
+
+    /// 是否应该为这次读数触发 `burn_rate_alarm`：eta 低于阈值且不在去抖窗口内
+    pub fn should_alarm(&self, reading: &BurnRateReading) -> bool {
+        let Some(eta) = reading.eta_seconds else { return false };
+        if eta >= ALARM_ETA_THRESHOLD.as_secs_f64() {
+            return false;
+        }
+
+        let mut last_alarm_at = self.last_alarm_at.lock().unwrap();
+        let should_fire = last_alarm_at
+            .map(|t| t.elapsed() >= ALARM_DEBOUNCE)
+            .unwrap_or(true);
+        if should_fire {
+            *last_alarm_at = Some(Instant::now());
+        }
+        should_fire
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_sample_has_no_rate_or_eta() {
+        let tracker = BurnRateTracker::new();
+        let reading = tracker.sample(10.0, 100.0);
+        assert_eq!(reading.rate_per_sec, 0.0);
+        assert!(reading.eta_seconds.is_none());
+    }
+
+    #[test]
+    fn two_samples_derive_rate_and_eta_from_window_endpoints() {
+        let tracker = BurnRateTracker::new();
+        {
+            let mut window = tracker.window.lock().unwrap();
+            window.push_back(Sample { at: Instant::now() - Duration::from_secs(10), cost: 0.0 });
+        }
+
+        let reading = tracker.sample(20.0, 100.0);
+        assert!((reading.rate_per_sec - 2.0).abs() < 0.1);
+        let eta = reading.eta_seconds.expect("positive rate must produce an eta");
+        assert!((eta - 40.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn reset_clears_window_and_alarm_debounce() {
+        let tracker = BurnRateTracker::new();
+        tracker.sample(10.0, 100.0);
+        *tracker.last_alarm_at.lock().unwrap() = Some(Instant::now());
+
+        tracker.reset();
+
+        assert!(tracker.window.lock().unwrap().is_empty());
+        assert!(tracker.last_alarm_at.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn should_alarm_false_when_eta_above_threshold() {
+        let tracker = BurnRateTracker::new();
+        let reading = BurnRateReading { rate_per_sec: 1.0, eta_seconds: Some(ALARM_ETA_THRESHOLD.as_secs_f64() + 1.0) };
+        assert!(!tracker.should_alarm(&reading));
+    }
+
+    #[test]
+    fn should_alarm_fires_once_then_debounces_until_window_elapses() {
+        let tracker = BurnRateTracker::new();
+        let reading = BurnRateReading { rate_per_sec: 1.0, eta_seconds: Some(1.0) };
+
+        assert!(tracker.should_alarm(&reading));
+        // 刚报过警，去抖窗口内的第二次读数不应该重复报警
+        assert!(!tracker.should_alarm(&reading));
+
+        // 手动把上次报警时间拨回去抖窗口之外，模拟时间流逝
+        *tracker.last_alarm_at.lock().unwrap() = Some(Instant::now() - ALARM_DEBOUNCE - Duration::from_secs(1));
+        assert!(tracker.should_alarm(&reading));
+    }
+}
@@ -3,137 +3,297 @@ use serde_json::{json, Value};
 use std::sync::{Arc, Mutex};
 use anyhow::anyhow;
 use redis::AsyncCommands;
-use tokio::sync::Mutex as TokioMutex;
+use crate::redis_pool::RedisPoolManager;
 use std::collections::HashMap;
-use crate::types::PriceInfo;
+use crate::types::ModelPricing;
 use crate::types;
+use crate::throttle::{ProviderThrottle, ThrottleWait};
+use crate::crypto::MessageCipher;
+use crate::storage::{HistoryStore, PriceStore};
+use sha2::{Digest, Sha256};
+use rust_decimal::prelude::*;
 
 // 🆕 [双库分离] 定义过期时间常量（24小时）
 const CHAT_HISTORY_TTL: u64 = 86400; // 24 * 60 * 60 = 86400 秒
 
+// 🏊 [连接池] bb8 池的默认参数，均可通过环境变量覆盖
+const DEFAULT_POOL_MAX_SIZE: u32 = 20;
+const DEFAULT_POOL_MIN_IDLE: u32 = 2;
+const DEFAULT_POOL_TIMEOUT_SECS: u64 = 5;
+
+// 📊 [价格风控] 单次价格变动超过这个百分比就触发告警，可通过 `PRICE_ALERT_THRESHOLD_PCT` 覆盖
+const DEFAULT_PRICE_ALERT_THRESHOLD_PCT: f64 = 10.0;
+
+/// 涨跌幅百分比；旧价为 0 时（旧记录里价格为 0 或缺少该字段）直接视为 100% 涨幅，避免除零
+fn pct_delta(old: f64, new: f64) -> f64 {
+    if old == 0.0 {
+        if new == 0.0 { 0.0 } else { 100.0 }
+    } else {
+        (new - old) / old.abs() * 100.0
+    }
+}
+
+/// 🔐 [完整性校验] 把字节串转成小写十六进制字符串
+fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// 🔐 [完整性校验] 单个模型的叶子哈希：`SHA256(model_id || input_price || output_price)`
+/// 价格用定长小数格式化后再参与哈希，保证同一个值无论写入还是回读都能算出同一个叶子
+fn price_leaf_hash(model_id: &str, input_price: Decimal, output_price: Decimal) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(model_id.as_bytes());
+    hasher.update(format!("{:.12}", input_price).as_bytes());
+    hasher.update(format!("{:.12}", output_price).as_bytes());
+    bytes_to_hex(&hasher.finalize())
+}
+
+/// 🔐 [完整性校验] 按 model_id 排序后把叶子哈希首尾相连，再整体 SHA256 一次得到根哈希
+/// （Merkle 风格的简化版：只有一层合并，足够检测"整体是否和某次同步结果一致"）
+fn price_merkle_root(prices: &HashMap<String, ModelPricing>) -> (String, Vec<(String, String)>) {
+    let mut leaves: Vec<(String, String)> = prices
+        .iter()
+        .map(|(model_id, info)| (model_id.clone(), price_leaf_hash(model_id, info.input_price, info.output_price)))
+        .collect();
+    leaves.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hasher = Sha256::new();
+    for (_, leaf) in &leaves {
+        hasher.update(leaf.as_bytes());
+    }
+    (bytes_to_hex(&hasher.finalize()), leaves)
+}
+
 pub struct Client {
     client: ReqwestClient,
     pub dashscope_api_key: String,
     pub deepseek_api_key: String,
     pub zhipu_ai_key: String,
-    // ✅ 核心：Mutex 保护 Option 保证初始化安全，内层 TokioMutex 保证异步 Redis 操作安全
-    pub redis_client: Arc<Mutex<Option<Arc<TokioMutex<redis::aio::MultiplexedConnection>>>>>,
-    
-    // 📚 DB0：专门负责价格查询
-    pub redis_price_db: Arc<Mutex<Option<Arc<TokioMutex<redis::aio::MultiplexedConnection>>>>>,
-    
-    // 📚 DB1：专门负责聊天历史（实现跨模型续聊 + 自动清理）
-    pub redis_chat_db: Arc<Mutex<Option<Arc<TokioMutex<redis::aio::MultiplexedConnection>>>>>,
-    
+
+    // 🏊 [连接池] DB0：专门负责价格查询；Mutex<Option<..>> 保证首次建池的初始化安全，
+    // bb8::Pool 本身内部已经是 Arc 化的，clone 出来后多个调用方可以并发 `.get().await`
+    pub redis_price_pool: Arc<Mutex<Option<bb8::Pool<RedisPoolManager>>>>,
+
+    // 🏊 [连接池] DB1：专门负责聊天历史（实现跨模型续聊 + 自动清理）
+    pub redis_chat_pool: Arc<Mutex<Option<bb8::Pool<RedisPoolManager>>>>,
+
     pub redis_url: String,
     pub currency_base: String, // "USD" or "CNY"
     // 🛡️ 影子保护：防止特定模型被自动同步覆盖
     pub protected_models: Vec<String>,
+    // 🧊 [限流保护] 按 provider 冻结上游 429 请求
+    pub throttle: Arc<ProviderThrottle>,
+    // 🛡️ [静态加密] 聊天历史 / 价格缓存落盘前的可选 AEAD 加密层；未配置密钥时是纯直通
+    pub message_cipher: MessageCipher,
+    // 📊 [价格风控] 价格变动超过阈值时在这个 watch 通道上推送结构化告警，调用方 `subscribe()` 即可
+    pub price_alert_tx: tokio::sync::watch::Sender<Value>,
+    // 🗄️ [存储后端] 价格表 / 聊天历史的实际读写后端，由 `STORAGE_BACKEND` 环境变量选择
+    pub price_store: Arc<dyn PriceStore>,
+    pub history_store: Arc<dyn HistoryStore>,
+    // 💱 [币种识别] 取代散落在各计费函数里的硬编码判断，规则可通过 `CURRENCY_RESOLVER_CONFIG` 覆盖
+    pub currency_resolver: types::CurrencyResolver,
+    // 💱 [汇率预言机] 取代 DeepSeek 换算里硬编码的 `7.2`，后台周期刷新 + 缓存 + TTL 兜底
+    pub fx_oracle: Arc<crate::fx_oracle::FxRateOracle>,
 }
 
 impl Client {
-    /// ✅ 异步初始化 Redis 连接（双库分离）
+    /// 🏊 [连接池] 按 `REDIS_POOL_MAX_SIZE` / `REDIS_POOL_MIN_IDLE` / `REDIS_POOL_TIMEOUT_SECS`
+    /// 装配一个 bb8 连接池；三者缺省时分别回退到 20 / 2 / 5s。
+    async fn build_pool(url: &str) -> Result<bb8::Pool<RedisPoolManager>, anyhow::Error> {
+        let max_size = std::env::var("REDIS_POOL_MAX_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_POOL_MAX_SIZE);
+        let min_idle = std::env::var("REDIS_POOL_MIN_IDLE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_POOL_MIN_IDLE);
+        let timeout_secs = std::env::var("REDIS_POOL_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_POOL_TIMEOUT_SECS);
+
+        let manager = RedisPoolManager::new(url)?;
+        let pool = bb8::Pool::builder()
+            .max_size(max_size)
+            .min_idle(Some(min_idle))
+            .connection_timeout(std::time::Duration::from_secs(timeout_secs))
+            .build(manager)
+            .await?;
+        Ok(pool)
+    }
+
+    /// 📊 [价格风控] 订阅价格变动告警；每次 `record_price_point_and_alert` 判定涨跌幅超过
+    /// 阈值都会往这个通道推一条结构化告警（旧价、新价、涨跌幅、vendor）
+    pub fn subscribe_price_alerts(&self) -> tokio::sync::watch::Receiver<Value> {
+        self.price_alert_tx.subscribe()
+    }
+
+    fn price_pool(&self) -> Option<bb8::Pool<RedisPoolManager>> {
+        self.redis_price_pool.lock().unwrap().clone()
+    }
+
+    fn chat_pool(&self) -> Option<bb8::Pool<RedisPoolManager>> {
+        self.redis_chat_pool.lock().unwrap().clone()
+    }
+
+    /// ✅ 异步初始化 Redis 连接池（双库分离）
     pub async fn init_redis(&self) -> Result<(), anyhow::Error> {
-        // 先检查是否已经连上了（检查 DB0 和 DB1）
-        {
-            let price_guard = self.redis_price_db.lock().unwrap();
-            if price_guard.is_some() {
-                return Ok(());
-            }
-            
-            let chat_guard = self.redis_chat_db.lock().unwrap();
-            if chat_guard.is_some() {
-                return Ok(());
-            }
+        // 先检查是否已经建好了（DB0 和 DB1 都要有才算初始化完成，避免其中一个建池失败后被误判为"已就绪"）
+        if self.price_pool().is_some() && self.chat_pool().is_some() {
+            return Ok(());
         }
-        
-        println!("📡 [Redis] 正在连接: {}", self.redis_url);
+
+        println!("📡 [Redis] 正在建立连接池: {}", self.redis_url);
         let base_url = self.redis_url.trim_end_matches('/');
-        
-        // 🆕 [双库分离] 1. 初始化 DB0 (价格库)
-        let p_client = redis::Client::open(format!("{}/0", base_url))?;
-        let p_conn = p_client.get_multiplexed_async_connection().await?;
-        *self.redis_price_db.lock().unwrap() = Some(Arc::new(TokioMutex::new(p_conn)));
-        
-        // 🆕 [双库分离] 2. 初始化 DB1 (历史库)
-        let c_client = redis::Client::open(format!("{}/1", base_url))?;
-        let c_conn = c_client.get_multiplexed_async_connection().await?;
-        *self.redis_chat_db.lock().unwrap() = Some(Arc::new(TokioMutex::new(c_conn)));
-        
-        println!("✅ [哨兵] 数据库分工完成：DB0(价格计费) | DB1(历史记忆)");
+
+        // 🆕 [双库分离] 1. 初始化 DB0 (价格库) 连接池
+        let price_pool = Self::build_pool(&format!("{}/0", base_url)).await?;
+        *self.redis_price_pool.lock().unwrap() = Some(price_pool);
+
+        // 🆕 [双库分离] 2. 初始化 DB1 (历史库) 连接池
+        let chat_pool = Self::build_pool(&format!("{}/1", base_url)).await?;
+        *self.redis_chat_pool.lock().unwrap() = Some(chat_pool);
+
+        println!("✅ [哨兵] 数据库分工完成（bb8 连接池）：DB0(价格计费) | DB1(历史记忆)");
         Ok(())
     }
 
-    /// ✅ 从 Redis 获取历史对话（使用 DB1，支持跨模型续聊 + 断线重连）
+    /// ✅ 获取历史对话（支持跨模型续聊），实际读写委托给 `history_store`（Redis DB1 或 Postgres）
     pub async fn get_messages_from_redis(&self, session_id: &str) -> Result<Vec<Value>, anyhow::Error> {
-        let redis_conn = {
-            let guard = self.redis_chat_db.lock().unwrap();
-            guard.as_ref().map(|rc| Arc::clone(rc))
-        };
-        
-        if let Some(redis_conn) = redis_conn {
-            let key = format!("sentinel:chat:{}", session_id);
-            let mut conn = redis_conn.lock().await;
-            
-            // 从 DB1 获取该 session 的所有历史
-            let msgs: Vec<String> = conn.lrange(&key, 0, -1).await?;
-            let parsed_msgs = msgs.into_iter()
-                .filter_map(|m| serde_json::from_str(&m).ok())
-                .collect();
-            return Ok(parsed_msgs);
+        let msgs = self.history_store.load_session(session_id).await?;
+        if !msgs.is_empty() {
+            return Ok(msgs);
         }
-        
-        // 🆕 [断线重连] 如果没有连接，尝试重新初始化
-        println!("⚠️ [Redis] DB1 连接不存在，尝试重新初始化...");
+
+        // 🆕 [断线重连] 读到空列表时，Redis 后端可能是连接池还没建好，尝试重新初始化后再读一次
+        println!("⚠️ [存储] 未读到会话 [{}] 的历史，尝试重新初始化连接后重试...", session_id);
         self.init_redis().await?;
-        
-        // 重试一次
-        let redis_conn = {
-            let guard = self.redis_chat_db.lock().unwrap();
-            guard.as_ref().map(|rc| Arc::clone(rc))
-        };
-        
-        if let Some(redis_conn) = redis_conn {
-            let key = format!("sentinel:chat:{}", session_id);
-            let mut conn = redis_conn.lock().await;
-            
-            let msgs: Vec<String> = conn.lrange(&key, 0, -1).await?;
-            let parsed_msgs = msgs.into_iter()
-                .filter_map(|m| serde_json::from_str(&m).ok())
-                .collect();
-            return Ok(parsed_msgs);
+        self.history_store.load_session(session_id).await
+    }
+
+    /// 🛑 [优雅停机] 把累计成本落盘到 DB1，供下次启动时核对（不参与计费计算）
+    pub async fn save_cumulative_cost_to_redis(&self, total_cost: f64) -> Result<(), anyhow::Error> {
+        if let Some(pool) = self.chat_pool() {
+            let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB1)连接失败: {}", e))?;
+            let _: () = redis::cmd("SET")
+                .arg("sentinel:cumulative_cost")
+                .arg(total_cost.to_string())
+                .query_async(&mut *conn)
+                .await?;
         }
-        
-        Ok(vec![])
+        Ok(())
     }
 
-    /// ✅ 保存消息到 Redis（使用 DB1，支持跨模型续聊 + 自动清理）
+    /// 🩺 [连接监护] 对 DB0/DB1 各发一次 PING，全部成功才算健康
+    pub async fn ping_redis(&self) -> bool {
+        for pool in [self.price_pool(), self.chat_pool()] {
+            match pool {
+                Some(pool) => {
+                    let conn = pool.get().await;
+                    match conn {
+                        Ok(mut conn) => {
+                            let pong: Result<String, _> = redis::cmd("PING").query_async(&mut *conn).await;
+                            if pong.is_err() {
+                                return false;
+                            }
+                        }
+                        Err(_) => return false,
+                    }
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// 🩺 [连接监护] 丢弃当前连接池并重新建立（供监护任务在 PING 失败后调用）
+    pub async fn reconnect_redis(&self) -> Result<(), anyhow::Error> {
+        *self.redis_price_pool.lock().unwrap() = None;
+        *self.redis_chat_pool.lock().unwrap() = None;
+        self.init_redis().await
+    }
+
+    /// ✅ 保存消息（支持跨模型续聊 + 自动清理），实际读写委托给 `history_store`
     pub async fn save_messages_to_redis(&self, session_id: &str, message: &Value) -> Result<(), anyhow::Error> {
-        let redis_conn = {
-            let guard = self.redis_chat_db.lock().unwrap();
-            guard.as_ref().map(|rc| Arc::clone(rc))
-        };
-        
-        if let Some(redis_conn) = redis_conn {
-            let key = format!("sentinel:chat:{}", session_id);
-            let mut conn = redis_conn.lock().await;
-            
-            // 将消息转为 JSON 字符串存入列表
-            let _: () = conn.rpush(&key, message.to_string()).await?;
-            
-            // 🆕 [自动清理] 设置 24 小时过期，防止数据库撑爆
-            let _: () = conn.expire(&key, CHAT_HISTORY_TTL as i64).await?;
-            println!("💾 [Redis] 成功记录会话 [{}] 的新记忆 (TTL: 24h)", session_id);
+        self.history_store.append_message(session_id, message, CHAT_HISTORY_TTL).await?;
+        println!("💾 [存储] 成功记录会话 [{}] 的新记忆 (TTL: 24h)", session_id);
+        Ok(())
+    }
+
+    /// 🧊 [价格时间序列] 把本次观测到的价格点写入 `price:history:<model>` 有序集合
+    /// （score 是 UNIX 时间戳，member 是 `{input_price, output_price, ts}`），供
+    /// `get_price_history` 按时间区间回看。顺带和 Redis 里的旧值比较涨跌幅，超过
+    /// `PRICE_ALERT_THRESHOLD_PCT`（默认 10%）就在 `price_alert_tx` 上推一条结构化告警，
+    /// 避免厂商悄悄提价导致计费结果突然失真却没人发现。
+    async fn record_price_point_and_alert(&self, model_id: &str, vendor: &str, input_price: Decimal, output_price: Decimal) -> Result<(), anyhow::Error> {
+        // 🆕 [精确计费] 历史时间序列 / 告警走的是人类监控而非计费路径，落盘前转回 f64 即可
+        let input_price = input_price.to_f64().unwrap_or(0.0);
+        let output_price = output_price.to_f64().unwrap_or(0.0);
+
+        let Some(pool) = self.price_pool() else { return Ok(()) };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB0)连接失败: {}", e))?;
+
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let old_value: Option<String> = redis::cmd("GET").arg(format!("price:{}", model_id)).query_async(&mut *conn).await?;
+        if let Some(old_value) = old_value {
+            if let Ok(old_json) = serde_json::from_str::<Value>(&self.message_cipher.decrypt(&old_value)) {
+                let old_input = old_json["input_price"].as_f64().unwrap_or(0.0);
+                let old_output = old_json["output_price"].as_f64().unwrap_or(0.0);
+
+                let threshold_pct = std::env::var("PRICE_ALERT_THRESHOLD_PCT")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_PRICE_ALERT_THRESHOLD_PCT);
+
+                let input_pct = pct_delta(old_input, input_price);
+                let output_pct = pct_delta(old_output, output_price);
+
+                if input_pct.abs() > threshold_pct || output_pct.abs() > threshold_pct {
+                    let alert = json!({
+                        "model": model_id,
+                        "vendor": vendor,
+                        "old_input_price": old_input,
+                        "old_output_price": old_output,
+                        "new_input_price": input_price,
+                        "new_output_price": output_price,
+                        "input_pct_delta": input_pct,
+                        "output_pct_delta": output_pct,
+                        "ts": ts,
+                    });
+                    println!("📊 [价格风控] {} 价格变动超过阈值 {:.1}%：{}", model_id, threshold_pct, alert);
+                    let _ = self.price_alert_tx.send(alert);
+                }
+            }
         }
+
+        let point = json!({ "input_price": input_price, "output_price": output_price, "ts": ts }).to_string();
+        let _: () = conn.zadd(format!("price:history:{}", model_id), point, ts as f64).await?;
+
         Ok(())
     }
 
+    /// 📊 [价格时间序列] 按 `[from_ts, to_ts]`（含端点）查询某个模型的历史价格点
+    pub async fn get_price_history(&self, model: &str, from_ts: u64, to_ts: u64) -> Result<Vec<Value>, anyhow::Error> {
+        if let Some(pool) = self.price_pool() {
+            let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB0)连接失败: {}", e))?;
+            let members: Vec<String> = conn.zrangebyscore(format!("price:history:{}", model), from_ts, to_ts).await?;
+            Ok(members.iter().filter_map(|m| serde_json::from_str(m).ok()).collect())
+        } else {
+            Ok(vec![])
+        }
+    }
+
     /// 🚀 终极方案：从 LiteLLM GitHub 自动同步价格并归一化单位（使用 DB0）
     pub async fn sync_litellm_prices(&self) -> Result<(), anyhow::Error> {
         println!("📡 [同步] 正在从 LiteLLM 获取最新价格情报...");
-        
+
         let url = "https://raw.githubusercontent.com/BerriAI/litellm/main/model_prices_and_context_window.json";
-        
+
         // 🆕 [错误处理] 添加详细的错误日志
         let response = match self.client.get(url).send().await {
             Ok(resp) => {
@@ -146,13 +306,10 @@ impl Client {
                 return Err(anyhow!("请求 GitHub 失败: {}", e));
             }
         };
-        
-        // 🆕 [双库分离] 使用 DB0 (价格库) 存储价格
-        let redis_conn = {
-            let guard = self.redis_price_db.lock().unwrap();
-            guard.as_ref().map(|rc| Arc::clone(rc))
-        };
-        
+
+        // 🔐 [完整性校验] 本轮同步实际写入的模型，同步完成后用来算 Merkle 根
+        let mut synced_prices: HashMap<String, ModelPricing> = HashMap::new();
+
         if let Some(models) = response.as_object() {
             for (model_id, info) in models {
                 // 1. 提取单 token 价格
@@ -162,13 +319,13 @@ impl Client {
                 let output_per_token = info.get("output_cost_per_token")
                     .and_then(|v| v.as_f64())
                     .unwrap_or(0.0);
-                
+
                 // 2. ⚡️ 过滤掉价格为0的模型
                 if input_per_token == 0.0 && output_per_token == 0.0 {
                     println!("⚠️ [跳过] {}（价格为0）", model_id);
                     continue;
                 }
-                
+
                 // 3. ⚡️ 过滤掉带后缀的模型
                 let suffix_patterns = [
                     "instruct",
@@ -177,13 +334,13 @@ impl Client {
                     "-v1:0",
                     ":0",
                 ];
-                
+
                 let has_suffix = suffix_patterns.iter().any(|suffix| model_id.ends_with(suffix));
                 if has_suffix {
                     println!("⚠️ [跳过] {}（包含后缀）", model_id);
                     continue;
                 }
-                
+
                 // 4. ⚡️ 过滤掉带日期的模型
                 let date_patterns = [
                     r"-20\d{6}",           // -20250807
@@ -197,7 +354,7 @@ impl Client {
                     r"-preview-\d{2}-\d{2}",  // -preview-03-25
                     r"-\d{4}-\d{2}-\d{2}",  // -2025-12-16
                 ];
-                
+
                 let has_date = date_patterns.iter().any(|pattern| {
                     if let Ok(re) = regex::Regex::new(pattern) {
                         re.is_match(model_id)
@@ -205,39 +362,49 @@ impl Client {
                         false
                     }
                 });
-                
+
                 if has_date {
                     println!("⚠️ [跳过] {}（包含日期）", model_id);
                     continue;
                 }
-                
-                // 5. ⚡️ 核心转换：直接使用每token价格（避免精度丢失）
-                let input_price = input_per_token;
-                let output_price = output_per_token;
-                
+
+                // 5. ⚡️ 核心转换：直接使用每token价格（避免精度丢失），落入 Decimal 避免后续累乘累加时漂移
+                let input_price = Decimal::from_f64(input_per_token).unwrap_or(Decimal::ZERO);
+                let output_price = Decimal::from_f64(output_per_token).unwrap_or(Decimal::ZERO);
+
                 // 6. 归一化 Key（去掉所有前缀）并存入 Redis
                 let clean_name = types::normalize_model_name(model_id);
-                
+
                 // 🛡️ 影子保护：检查是否是受保护的模型
                 if self.protected_models.contains(&clean_name) {
                     println!("⚠️ [跳过] {}（在保护名单中，保留本地备份）", clean_name);
                     continue;
                 }
-                
-                let price_data = json!({
-                    "input_price": input_price,
-                    "output_price": output_price,
-                    "vendor": "litellm_auto"
+
+                // 💱 [币种识别] 这批价格来自 litellm 的 `*_cost_per_token`，本身大多是美元计价，
+                // 但 qwen/glm/zhipu/yi 等厂商仍按人民币计价——落盘前用同一套 resolver 判一次，
+                // 不再写死 Usd，否则 get_all_prices 读回来的 currency 和实际单价对不上
+                let (stored_currency, _) = self.currency_resolver.resolve(&clean_name, input_price);
+
+                // 📊 [价格时间序列] 先和旧值比对涨跌幅并记录历史点（仅 Redis 后端有这套时间序列），再覆盖当前价格
+                self.record_price_point_and_alert(&clean_name, "litellm_auto", input_price, output_price).await?;
+                self.price_store.set_price(&clean_name, input_price, output_price, "litellm_auto", stored_currency).await?;
+                synced_prices.insert(clean_name.clone(), ModelPricing {
+                    input_price,
+                    output_price,
+                    currency: stored_currency,
+                    price_scale: 6,
+                    stored_unit: types::PriceUnit::PerToken,
                 });
-                
-                if let Some(ref conn_arc) = redis_conn {
-                    let mut conn = conn_arc.lock().await;
-                    let _: () = redis::cmd("SET").arg(format!("price:{}", clean_name)).arg(price_data.to_string()).query_async(&mut *conn).await?;
-                    println!("💾 [Redis] 已更新价格: {} (输入: {:.9}, 输出: {:.9})", clean_name, input_price, output_price);
-                }
+                println!("💾 [存储] 已更新价格: {} (输入: {:.9}, 输出: {:.9})", clean_name, input_price, output_price);
             }
         }
-        
+
+        // 🔐 [完整性校验] 算出这批价格的 Merkle 根并落盘，供 verify_price_integrity 核对
+        if let Err(e) = self.store_price_integrity_root(&synced_prices).await {
+            println!("⚠️ [完整性校验] 写入价格根哈希失败: {}", e);
+        }
+
         println!("✅ [同步] 已自动更新全网模型价格，单位已统一为 USD/1M Tokens");
         Ok(())
     }
@@ -263,28 +430,15 @@ impl Client {
         }
     }
 
-    /// ✅ 保存单个价格到 Redis DB（优先保留 official_manual 标记的价格）
-    async fn save_price_to_redis(&self, model_id: &str, input_price: f64, output_price: f64) -> Result<(), anyhow::Error> {
-        let redis_conn = {
-            let guard = self.redis_price_db.lock().unwrap();
-            guard.as_ref().map(|rc| Arc::clone(rc))
-        };
+    /// ✅ 保存单个价格（优先保留 official_manual 标记的价格），实际读写委托给 `price_store`
+    async fn save_price_to_redis(&self, model_id: &str, input_price: Decimal, output_price: Decimal) -> Result<(), anyhow::Error> {
+        // 📊 [价格时间序列] 先和旧值比对涨跌幅并记录历史点（仅 Redis 后端有这套时间序列），再覆盖当前价格
+        self.record_price_point_and_alert(model_id, "litellm", input_price, output_price).await?;
+
+        let (stored_currency, _) = self.currency_resolver.resolve(model_id, input_price);
+        self.price_store.set_price(model_id, input_price, output_price, "litellm", stored_currency).await?;
+        println!("💾 [存储] 已保存价格: {} (输入: {:.6}, 输出: {:.6})", model_id, input_price, output_price);
 
-        if let Some(redis_conn) = redis_conn {
-            let mut conn = redis_conn.lock().await;
-            let key = format!("price:{}", model_id);
-            
-            // 🆕 [强制覆盖] 直接保存价格，不包含日期字段
-            let value = json!({
-                "vendor": "litellm",
-                "input_price": input_price,
-                "output_price": output_price
-            });
-            let _: () = redis::cmd("SET").arg(&key).arg(value.to_string()).query_async(&mut *conn).await?;
-            println!("💾 [Redis] 已保存价格: {} (输入: {:.6}, 输出: {:.6})", 
-                model_id, input_price, output_price);
-        }
-        
         Ok(())
     }
 
@@ -292,15 +446,17 @@ impl Client {
     pub async fn sync_all_vendor_prices(&self) -> Result<(), anyhow::Error> {
         let currency = if self.currency_base == "USD" { "美元" } else { "人民币" };
         println!("📡 [哨兵情报站] 正在从 GitHub litellm 提取全球模型定价（本位：{}）...", currency);
-        
+
         let url = "https://raw.githubusercontent.com/BerriAI/litellm/main/model_prices_and_context_window.json";
         let resp: Value = self.client.get(url).send().await?.json().await?;
-        
+
         let _usd_to_cny = 7.25;
         let _safety_margin = 1.1;
         let _use_cny = self.currency_base == "CNY";
         let mut count = 0;
-        
+        // 🔐 [完整性校验] 本轮同步实际写入的模型，同步完成后用来算 Merkle 根
+        let mut synced_prices: HashMap<String, ModelPricing> = HashMap::new();
+
         if let Some(models) = resp.as_object() {
             for (model_id, model_data) in models {
                 // 获取价格信息
@@ -310,59 +466,60 @@ impl Client {
                 let output_price_usd = model_data.get("output_price_per_token")
                     .and_then(|v| v.as_f64())
                     .unwrap_or(0.0);
-                
-                // ⚡️ 核心转换：直接使用每token价格（避免精度丢失）
-                let input_price = input_price_usd;
-                let output_price = output_price_usd;
-                
+
+                // ⚡️ 核心转换：直接使用每token价格（避免精度丢失），落入 Decimal 避免后续累乘累加时漂移
+                let input_price = Decimal::from_f64(input_price_usd).unwrap_or(Decimal::ZERO);
+                let output_price = Decimal::from_f64(output_price_usd).unwrap_or(Decimal::ZERO);
+
                 // 简化模型名（使用增强的归一化函数）
                 let simplified_id = types::normalize_model_name(model_id);
-                
+
                 // 🛡️ 影子保护：检查是否是受保护的模型
                 if self.protected_models.contains(&simplified_id) {
                     println!("⚠️ [跳过] {}（在保护名单中，保留本地备份）", simplified_id);
                     continue;
                 }
-                
-                // 保存到 Redis
-                let price_data = json!({
-                    "input_price": input_price,
-                    "output_price": output_price,
-                    "vendor": "litellm_auto"
+
+                let (stored_currency, _) = self.currency_resolver.resolve(&simplified_id, input_price);
+
+                // 📊 [价格时间序列] 先和旧值比对涨跌幅并记录历史点（仅 Redis 后端有这套时间序列），再覆盖当前价格
+                self.record_price_point_and_alert(&simplified_id, "litellm_auto", input_price, output_price).await?;
+                self.price_store.set_price(&simplified_id, input_price, output_price, "litellm_auto", stored_currency).await?;
+                synced_prices.insert(simplified_id.clone(), ModelPricing {
+                    input_price,
+                    output_price,
+                    currency: stored_currency,
+                    price_scale: 6,
+                    stored_unit: types::PriceUnit::PerToken,
                 });
-                
-                let redis_conn = {
-                    let guard = self.redis_price_db.lock().unwrap();
-                    guard.as_ref().map(|rc| Arc::clone(rc))
-                };
-                
-                if let Some(ref conn_arc) = redis_conn {
-                    let mut conn = conn_arc.lock().await;
-                    let _: () = redis::cmd("SET").arg(format!("price:{}", simplified_id)).arg(price_data.to_string()).query_async(&mut *conn).await?;
-                    count += 1;
-                    println!("💾 [Redis] 已更新价格: {} (输入: {:.9}, 输出: {:.9})", simplified_id, input_price, output_price);
-                }
+                count += 1;
+                println!("💾 [存储] 已更新价格: {} (输入: {:.9}, 输出: {:.9})", simplified_id, input_price, output_price);
             }
             println!("✅ [情报站] 已成功物理同步 {} 个模型。", count);
+
+            // 🔐 [完整性校验] 算出这批价格的 Merkle 根并落盘，供 verify_price_integrity 核对
+            if let Err(e) = self.store_price_integrity_root(&synced_prices).await {
+                println!("⚠️ [完整性校验] 写入价格根哈希失败: {}", e);
+            }
         }
-        
+
         Ok(())
     }
 
     /// ✅ 核心对话接口
     pub async fn chat_completion(
-        &self, 
-        model: &str, 
-        payload: Value, 
-        _session_id: &str 
+        &self,
+        model: &str,
+        payload: Value,
+        _session_id: &str
     ) -> Result<reqwest::Response, anyhow::Error> {
         let simplified_model = self.simplify_model_id(model);
-        let (url, api_key) = if simplified_model.contains("qwen") || simplified_model.contains("qwq") {
-            ("https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions", &self.dashscope_api_key)
+        let (provider, url, api_key) = if simplified_model.contains("qwen") || simplified_model.contains("qwq") {
+            ("dashscope", "https://dashscope.aliyuncs.com/compatible-mode/v1/chat/completions", &self.dashscope_api_key)
         } else if simplified_model.contains("glm") {
-            ("https://open.bigmodel.cn/api/paas/v4/chat/completions", &self.zhipu_ai_key)
+            ("zhipu", "https://open.bigmodel.cn/api/paas/v4/chat/completions", &self.zhipu_ai_key)
         } else if simplified_model.contains("deepseek") {
-            ("https://api.deepseek.com/chat/completions", &self.deepseek_api_key)
+            ("deepseek", "https://api.deepseek.com/chat/completions", &self.deepseek_api_key)
         } else {
             return Err(anyhow!("⚠️ 哨兵提示：不支持该模型系列的官方直连"));
         };
@@ -374,7 +531,7 @@ impl Client {
         // ✅ 智能处理 stream_options：只有流模式才添加
         let mut final_payload = payload.clone();
         let is_stream = final_payload.get("stream").and_then(|v| v.as_bool()).unwrap_or(false);
-        
+
         if is_stream {
             if !final_payload.get("stream_options").is_some() {
                 final_payload["stream_options"] = json!({
@@ -388,71 +545,146 @@ impl Client {
             });
         }
 
-        let resp = self.client.post(url)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .json(&final_payload)
-            .send()
-            .await?;
+        // 🧊 [限流保护] provider 处于冻结期时先等待解冻，超时则直接拒绝
+        match self.throttle.wait_if_frozen(provider).await {
+            ThrottleWait::Rejected { retry_after_secs } => {
+                return Err(anyhow!(
+                    "⚠️ 哨兵提示：{} 当前被限流，请在 {} 秒后重试",
+                    provider,
+                    retry_after_secs
+                ));
+            }
+            ThrottleWait::Ready | ThrottleWait::WaitedThenReady => {}
+        }
+
+        loop {
+            let resp = self.client.post(url)
+                .header("Authorization", format!("Bearer {}", api_key))
+                .json(&final_payload)
+                .send()
+                .await?;
+
+            if resp.status().as_u16() != 429 {
+                self.throttle.record_success(provider);
+                return Ok(resp);
+            }
+
+            if self.throttle.should_give_up(provider) {
+                return Err(anyhow!("⚠️ 哨兵提示：{} 连续 429，已放弃重试", provider));
+            }
+
+            let retry_after = resp
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| v.to_str().ok())
+                .and_then(crate::throttle::parse_retry_after);
 
-        Ok(resp)
+            let delay = self.throttle.freeze_on_429(provider, retry_after);
+            println!("🧊 [限流] {} 返回 429，冻结 {:.1}s 后自动重试", provider, delay.as_secs_f64());
+            tokio::time::sleep(delay).await;
+        }
     }
 
     /// ✅ 从非流式响应中提取 usage 并计算成本
-    pub async fn extract_usage_from_response(model: &str, response: reqwest::Response, price_cache: &HashMap<String, types::PriceInfo>) -> Result<f64, anyhow::Error> {
+    pub async fn extract_usage_from_response(model: &str, response: reqwest::Response, price_cache: &HashMap<String, types::ModelPricing>, resolver: &types::CurrencyResolver, oracle: &crate::fx_oracle::FxRateOracle) -> Result<Decimal, anyhow::Error> {
         let simplified_model = model.to_lowercase().trim().to_string();
-        
+
         // 读取响应体（异步方式）
         let response_bytes = response.bytes().await?;
         let response_json: Value = serde_json::from_slice(&response_bytes)?;
-        
+
         println!("🔍 [DEBUG] 非流响应 JSON: {}", response_json);
-        
+
         // 检查是否有 usage 字段
         if let Some(usage) = response_json.get("usage") {
             let usage_struct: types::Usage = serde_json::from_value(usage.clone())?;
-            let (cost, _currency) = types::calculate_actual_cost(&simplified_model, &usage_struct, price_cache);
+            let (cost, _currency) = types::calculate_actual_cost(&simplified_model, &usage_struct, price_cache, resolver, oracle);
             println!("💰 [DEBUG] 非流模式计算成本: {} 元", cost);
             Ok(cost)
         } else {
             println!("⚠️ [DEBUG] 非流响应中未找到 usage 字段");
-            Ok(0.0)
+            Ok(Decimal::ZERO)
         }
     }
 
-    pub async fn get_all_prices_from_redis(&self) -> Result<HashMap<String, PriceInfo>, anyhow::Error> {
-        let redis_conn = {
-            let guard = self.redis_price_db.lock().unwrap();
-            guard.as_ref().map(|rc| Arc::clone(rc))
+    /// 🗄️ 加载全部模型价格，实际读写委托给 `price_store`（Redis DB0 或 Postgres）
+    pub async fn get_all_prices_from_redis(&self) -> Result<HashMap<String, ModelPricing>, anyhow::Error> {
+        let prices = self.price_store.get_all_prices().await?;
+        println!("🔄 [存储] 已从数据库加载 {} 个模型价格", prices.len());
+        Ok(prices)
+    }
+
+    /// 🔐 [完整性校验] 把本轮同步的 Merkle 根和各模型的叶子哈希写到 `price:root` / `price:leaves`
+    async fn store_price_integrity_root(&self, prices: &HashMap<String, ModelPricing>) -> Result<(), anyhow::Error> {
+        let Some(pool) = self.price_pool() else { return Ok(()) };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB0)连接失败: {}", e))?;
+
+        let (root, leaves) = price_merkle_root(prices);
+        let leaves_map: serde_json::Map<String, Value> = leaves.into_iter().map(|(model_id, hash)| (model_id, json!(hash))).collect();
+
+        let root_value = json!({ "root": root, "model_count": prices.len() });
+        let _: () = redis::cmd("SET").arg("price:root").arg(root_value.to_string()).query_async(&mut *conn).await?;
+        let _: () = redis::cmd("SET").arg("price:leaves").arg(Value::Object(leaves_map).to_string()).query_async(&mut *conn).await?;
+
+        println!("🔐 [完整性校验] 已写入价格根哈希: {} (模型数: {})", root, prices.len());
+        Ok(())
+    }
+
+    /// 🔐 [完整性校验] 重新加载 `price:*`（经 `get_all_prices_from_redis`），按相同规则重算
+    /// Merkle 根，和 `price:root` 比对；同时逐模型比对叶子哈希，找出具体哪些模型的价格
+    /// 和上次同步时写入的不一致（被篡改、被手工改过，或者同步中途失败导致部分写入）
+    pub async fn verify_price_integrity(&self) -> Result<Value, anyhow::Error> {
+        let prices = self.get_all_prices_from_redis().await?;
+        let (computed_root, leaves) = price_merkle_root(&prices);
+        let computed_leaves: HashMap<String, String> = leaves.into_iter().collect();
+
+        let Some(pool) = self.price_pool() else {
+            return Ok(json!({ "matches": false, "reason": "DB0 连接池不可用", "computed_root": computed_root }));
         };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB0)连接失败: {}", e))?;
 
-        if let Some(redis_conn) = redis_conn {
-            let mut conn = redis_conn.lock().await;
-            let keys: Vec<String> = redis::cmd("KEYS").arg("price:*").query_async(&mut *conn).await?;
-            
-            let mut prices = HashMap::new();
-            for key in keys {
-                let value: Option<String> = redis::cmd("GET").arg(&key).query_async(&mut *conn).await?;
-                if let Some(v) = value {
-                    if let Ok(json) = serde_json::from_str::<Value>(&v) {
-                        if let (Some(input_price), Some(output_price)) = (
-                            json["input_price"].as_f64(),
-                            json["output_price"].as_f64()
-                        ) {
-                            let model_id = key.trim_start_matches("price:");
-                            prices.insert(model_id.to_string(), PriceInfo {
-                                input_price,
-                                output_price
-                            });
-                        }
-                    }
-                }
+        let stored_root_raw: Option<String> = redis::cmd("GET").arg("price:root").query_async(&mut *conn).await?;
+        let Some(stored_root_raw) = stored_root_raw else {
+            return Ok(json!({
+                "matches": false,
+                "reason": "未找到 price:root，可能尚未执行过 sync_litellm_prices",
+                "computed_root": computed_root,
+                "computed_model_count": prices.len(),
+            }));
+        };
+
+        let stored_json: Value = serde_json::from_str(&stored_root_raw).unwrap_or(json!({}));
+        let stored_root = stored_json["root"].as_str().unwrap_or("").to_string();
+        let stored_model_count = stored_json["model_count"].as_u64().unwrap_or(0);
+
+        let stored_leaves_raw: Option<String> = redis::cmd("GET").arg("price:leaves").query_async(&mut *conn).await?;
+        let stored_leaves: HashMap<String, String> = stored_leaves_raw
+            .and_then(|raw| serde_json::from_str::<HashMap<String, String>>(&raw).ok())
+            .unwrap_or_default();
+
+        // 叶子哈希对不上（或者只在其中一边出现）的模型，都算作差异
+        let mut differing_models: Vec<String> = stored_leaves
+            .iter()
+            .filter(|(model_id, stored_leaf)| computed_leaves.get(*model_id) != Some(*stored_leaf))
+            .map(|(model_id, _)| model_id.clone())
+            .collect();
+        for model_id in computed_leaves.keys() {
+            if !stored_leaves.contains_key(model_id) && !differing_models.contains(model_id) {
+                differing_models.push(model_id.clone());
             }
-            
-            println!("🔄 [Redis] 已从数据库加载 {} 个模型价格", prices.len());
-            Ok(prices)
-        } else {
-            Ok(HashMap::new())
         }
+        differing_models.sort();
+
+        let matches = stored_root == computed_root && differing_models.is_empty();
+
+        Ok(json!({
+            "matches": matches,
+            "stored_root": stored_root,
+            "computed_root": computed_root,
+            "stored_model_count": stored_model_count,
+            "computed_model_count": prices.len(),
+            "differing_models": differing_models,
+        }))
     }
 
     /// ✅ 构造函数：支持命令行注入，不再硬编码
@@ -466,13 +698,38 @@ impl Client {
         let zhipu_ai_key = std::env::var("ZHIPU_AI_KEY").unwrap_or_default();
         let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
         let currency_base = std::env::var("CURRENCY_BASE").unwrap_or_else(|_| "CNY".to_string());
-        
+
         if !["USD", "CNY"].contains(&currency_base.as_str()) {
             panic!("⚠️ CURRENCY_BASE 必须是 USD 或 CNY，当前值：{}", currency_base);
         }
-        
+
         println!("🌍 [哨兵] 币种本位设置为：{}", if currency_base == "USD" { "美元 (USD)" } else { "人民币 (CNY)" });
-        
+
+        // 🏊 [连接池] 池要到 init_redis() 里才真正建立，此处先占位
+        let redis_price_pool = Arc::new(Mutex::new(None));
+        let redis_chat_pool = Arc::new(Mutex::new(None));
+        let message_cipher = MessageCipher::from_env();
+
+        // 🗄️ [存储后端] 默认沿用 Redis；部署已经在跑 Postgres 时设 STORAGE_BACKEND=postgres
+        // 换一套没有 24h 淘汰窗口的持久化存储，需要同时配置 DATABASE_URL
+        let storage_backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| "redis".to_string());
+        let (price_store, history_store): (Arc<dyn PriceStore>, Arc<dyn HistoryStore>) =
+            match storage_backend.as_str() {
+                "postgres" => {
+                    let database_url = std::env::var("DATABASE_URL")
+                        .unwrap_or_else(|_| panic!("⚠️ STORAGE_BACKEND=postgres 时必须配置 DATABASE_URL"));
+                    (
+                        Arc::new(crate::storage::PostgresPriceStore::new(database_url.clone())),
+                        Arc::new(crate::storage::PostgresHistoryStore::new(database_url)),
+                    )
+                }
+                _ => (
+                    Arc::new(crate::storage::RedisPriceStore::new(redis_price_pool.clone(), message_cipher.clone())),
+                    Arc::new(crate::storage::RedisHistoryStore::new(redis_chat_pool.clone(), message_cipher.clone())),
+                ),
+            };
+        println!("🗄️ [存储后端] 价格 / 聊天历史后端：{}", storage_backend);
+
         Client {
             // 🆕 [性能优化] 添加 TCP 优化，减少流式传输延迟
             client: ReqwestClient::builder()
@@ -484,15 +741,73 @@ impl Client {
             dashscope_api_key,
             deepseek_api_key,
             zhipu_ai_key,
-            redis_client: Arc::new(Mutex::new(None)),
-            
-            // 🆕 [双库分离] 必须初始化这两个字段
-            redis_price_db: Arc::new(Mutex::new(None)),
-            redis_chat_db: Arc::new(Mutex::new(None)),
-            
+
+            redis_price_pool,
+            redis_chat_pool,
+
             redis_url,
             currency_base,
             protected_models: vec!["qwen-vl-max"].iter().map(|s| s.to_string()).collect(), // 🛡️ 影子保护：防止特定模型被自动同步覆盖
+            throttle: Arc::new(ProviderThrottle::new()),
+            message_cipher,
+            price_alert_tx: tokio::sync::watch::channel(Value::Null).0,
+            price_store,
+            history_store,
+            currency_resolver: types::CurrencyResolver::from_env(),
+            fx_oracle: Arc::new(crate::fx_oracle::FxRateOracle::from_env()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pct_delta_treats_zero_old_price_as_100_percent() {
+        assert_eq!(pct_delta(0.0, 1.0), 100.0);
+    }
+
+    #[test]
+    fn pct_delta_is_zero_when_both_old_and_new_are_zero() {
+        assert_eq!(pct_delta(0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn pct_delta_computes_signed_percentage_change() {
+        assert!((pct_delta(10.0, 11.0) - 10.0).abs() < 1e-9);
+        assert!((pct_delta(10.0, 9.0) - (-10.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bytes_to_hex_formats_lowercase_fixed_width() {
+        assert_eq!(bytes_to_hex(&[0x0a, 0xff, 0x01]), "0aff01");
+    }
+
+    #[test]
+    fn price_merkle_root_is_independent_of_hashmap_iteration_order() {
+        let mut a: HashMap<String, ModelPricing> = HashMap::new();
+        a.insert("zeta".to_string(), ModelPricing { input_price: Decimal::new(1, 3), output_price: Decimal::new(2, 3), currency: types::Currency::Usd, price_scale: 6, stored_unit: types::PriceUnit::PerToken });
+        a.insert("alpha".to_string(), ModelPricing { input_price: Decimal::new(3, 3), output_price: Decimal::new(4, 3), currency: types::Currency::Usd, price_scale: 6, stored_unit: types::PriceUnit::PerToken });
+
+        let mut b: HashMap<String, ModelPricing> = HashMap::new();
+        b.insert("alpha".to_string(), a["alpha"].clone());
+        b.insert("zeta".to_string(), a["zeta"].clone());
+
+        let (root_a, _) = price_merkle_root(&a);
+        let (root_b, _) = price_merkle_root(&b);
+        assert_eq!(root_a, root_b);
+    }
+
+    #[test]
+    fn price_merkle_root_changes_when_a_price_changes() {
+        let mut prices: HashMap<String, ModelPricing> = HashMap::new();
+        prices.insert("alpha".to_string(), ModelPricing { input_price: Decimal::new(1, 3), output_price: Decimal::new(2, 3), currency: types::Currency::Usd, price_scale: 6, stored_unit: types::PriceUnit::PerToken });
+        let (root_before, _) = price_merkle_root(&prices);
+
+        prices.get_mut("alpha").unwrap().input_price = Decimal::new(5, 3);
+        let (root_after, _) = price_merkle_root(&prices);
+
+        assert_ne!(root_before, root_after);
+    }
+}
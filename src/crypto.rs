@@ -0,0 +1,79 @@
+// 🛡️ [静态加密] 聊天历史 / 价格缓存落盘前的可选 AEAD 加密层
+//
+// Redis DB1 里存的是明文 JSON，任何有 Redis 访问权限的人都能读到完整对话。这里加一层
+// 可选加密：配置 `SENTINEL_ENCRYPTION_KEY` 后，写入 Redis 前用 ChaCha20-Poly1305 + 随机
+// nonce 加密，存储 `base64(nonce || ciphertext)`；读取时自动识别并解密，老的明文记录
+// 原样透传，不强制迁移存量数据。没有配置 key 时整条链路是纯粹的直通（no-op），行为与
+// 加密之前完全一致。
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone)]
+pub struct MessageCipher {
+    cipher: Option<ChaCha20Poly1305>,
+}
+
+impl MessageCipher {
+    /// 从 `SENTINEL_ENCRYPTION_KEY` 装配；未配置（或为空）时返回一个直通（no-op）的实例
+    pub fn from_env() -> Self {
+        let Some(raw) = std::env::var("SENTINEL_ENCRYPTION_KEY").ok().filter(|s| !s.is_empty()) else {
+            println!("ℹ️ [静态加密] 未配置 SENTINEL_ENCRYPTION_KEY，聊天历史 / 价格缓存将以明文存储");
+            return MessageCipher { cipher: None };
+        };
+
+        // 任意长度的配置值经 SHA-256 派生成固定 32 字节密钥，免去用户自己对齐长度的麻烦
+        let mut hasher = Sha256::new();
+        hasher.update(raw.as_bytes());
+        let key_bytes = hasher.finalize();
+        let cipher = ChaCha20Poly1305::new(key_bytes.as_slice().into());
+        println!("✅ [静态加密] 已启用聊天历史 / 价格缓存静态加密");
+        MessageCipher { cipher: Some(cipher) }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// 加密明文，返回 `base64(nonce || ciphertext)`；未配置密钥时原样返回明文
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let Some(cipher) = &self.cipher else { return plaintext.to_string() };
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce_bytes);
+        let nonce = Nonce::from_slice(&nonce_bytes);
+
+        match cipher.encrypt(nonce, plaintext.as_bytes()) {
+            Ok(ciphertext) => {
+                let mut payload = nonce_bytes.to_vec();
+                payload.extend_from_slice(&ciphertext);
+                STANDARD.encode(payload)
+            }
+            Err(e) => {
+                println!("⚠️ [静态加密] 加密失败，回退为明文存储: {}", e);
+                plaintext.to_string()
+            }
+        }
+    }
+
+    /// 解密一条存量条目：能认出是 `base64(nonce || ciphertext)` 就解密，否则原样透传
+    /// （兼容加密上线之前写入的明文记录，不需要一次性迁移存量数据）
+    pub fn decrypt(&self, stored: &str) -> String {
+        let Some(cipher) = &self.cipher else { return stored.to_string() };
+
+        let Some(decoded) = STANDARD.decode(stored).ok().filter(|d| d.len() > NONCE_LEN) else {
+            return stored.to_string();
+        };
+
+        let (nonce_bytes, ciphertext) = decoded.split_at(NONCE_LEN);
+        let nonce = Nonce::from_slice(nonce_bytes);
+        match cipher.decrypt(nonce, ciphertext) {
+            Ok(plaintext_bytes) => String::from_utf8(plaintext_bytes).unwrap_or_else(|_| stored.to_string()),
+            Err(_) => stored.to_string(),
+        }
+    }
+}
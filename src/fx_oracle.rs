@@ -0,0 +1,217 @@
+// 💱 [汇率预言机] 取代 DeepSeek 换算里硬编码的 `7.2`，改成一个后台周期刷新、带缓存 + TTL +
+// 兜底默认值的汇率源。目前只报一个方向的牌价（USD/CNY），`convert` 按 `from`/`to` 自己决定
+// 正算还是取倒数。只支持 USD 与 CNY 之间的换算，其它币种组合原样返回金额；刷新时也只读取
+// 端点响应里的 `usd_cny` 字段。
+//
+// 刷新策略：后台任务按 `FX_RATE_REFRESH_SECS`（默认 300 秒）周期性去 `FX_RATE_ENDPOINT` 拉一次，
+// 成功就覆盖缓存值 + 打时间戳；失败就保留旧缓存（旧值还没过 TTL 就继续用，过了 TTL 就降级到
+// `FX_RATE_DEFAULT_USD_CNY`）。`set_manual_override` 供运营临时锁定一个牌价（比如汇率源抽风时），
+// 优先级高于缓存和默认值，清空后自动恢复正常刷新。
+
+use rust_decimal::prelude::*;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::types::Currency;
+
+/// 缓存值过期前的存活时间：超过这个时长还没刷新成功，就认为缓存已经不可信，降级到默认值
+const DEFAULT_TTL: Duration = Duration::from_secs(3600);
+
+/// 后台刷新周期
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// 刷新失败 / 未配置端点时的兜底牌价（1 美元兑多少人民币），和迁移前硬编码的 `7.2` 保持一致
+const DEFAULT_RATE_USD_CNY: &str = "7.2";
+
+struct CachedRate {
+    rate_usd_cny: Decimal,
+    fetched_at: Instant,
+}
+
+pub struct FxRateOracle {
+    http_client: reqwest::Client,
+    endpoint: Option<String>,
+    ttl: Duration,
+    default_rate: Decimal,
+    cached: Mutex<Option<CachedRate>>,
+    manual_override: Mutex<Option<Decimal>>,
+}
+
+impl FxRateOracle {
+    /// 按 `FX_RATE_ENDPOINT` / `FX_RATE_DEFAULT_USD_CNY` / `FX_RATE_TTL_SECS` 装配；
+    /// 未配置 `FX_RATE_ENDPOINT` 时永远只用默认牌价（不会尝试联网）
+    pub fn from_env() -> Self {
+        let endpoint = std::env::var("FX_RATE_ENDPOINT").ok().filter(|s| !s.is_empty());
+
+        let default_rate = std::env::var("FX_RATE_DEFAULT_USD_CNY")
+            .ok()
+            .and_then(|v| Decimal::from_str(&v).ok())
+            .unwrap_or_else(|| Decimal::from_str(DEFAULT_RATE_USD_CNY).unwrap());
+
+        let ttl = std::env::var("FX_RATE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TTL);
+
+        FxRateOracle {
+            http_client: reqwest::Client::new(),
+            endpoint,
+            ttl,
+            default_rate,
+            cached: Mutex::new(None),
+            manual_override: Mutex::new(None),
+        }
+    }
+
+    /// 运营手动锁定一个牌价，优先级高于缓存 / 默认值；传 `None` 清除锁定，恢复正常刷新逻辑
+    pub fn set_manual_override(&self, rate: Option<Decimal>) {
+        *self.manual_override.lock().unwrap() = rate;
+    }
+
+    /// 当前生效的 USD/CNY 牌价：手动锁定 > 未过期的缓存 > 兜底默认值
+    fn current_rate_usd_cny(&self) -> Decimal {
+        if let Some(rate) = *self.manual_override.lock().unwrap() {
+            return rate;
+        }
+
+        if let Some(cached) = self.cached.lock().unwrap().as_ref() {
+            if cached.fetched_at.elapsed() < self.ttl {
+                return cached.rate_usd_cny;
+            }
+        }
+
+        self.default_rate
+    }
+
+    /// 把 `amount` 从 `from` 换算成 `to`；两者相同直接原样返回，不做任何乘除
+    pub fn convert(&self, amount: Decimal, from: Currency, to: Currency) -> Decimal {
+        if from == to {
+            return amount;
+        }
+
+        let rate_usd_cny = self.current_rate_usd_cny();
+        match (from, to) {
+            (Currency::Usd, Currency::Cny) => amount * rate_usd_cny,
+            (Currency::Cny, Currency::Usd) => amount / rate_usd_cny,
+            _ => amount,
+        }
+    }
+
+    /// 向 `FX_RATE_ENDPOINT` 发一次请求刷新缓存；端点返回形如 `{"usd_cny": 7.21}` 的 JSON。
+    /// 请求失败或字段缺失时静默保留旧缓存，由 `current_rate_usd_cny` 的 TTL 判断决定何时降级。
+    async fn refresh_once(&self) {
+        let Some(endpoint) = self.endpoint.as_ref() else { return };
+
+        let result = async {
+            let resp = self.http_client.get(endpoint).send().await?;
+            let json: serde_json::Value = resp.json().await?;
+            json.get("usd_cny")
+                .and_then(|v| v.as_f64())
+                .and_then(Decimal::from_f64)
+                .ok_or_else(|| anyhow::anyhow!("响应里没有 usd_cny 字段"))
+        }
+        .await;
+
+        match result {
+            Ok(rate) => {
+                *self.cached.lock().unwrap() = Some(CachedRate { rate_usd_cny: rate, fetched_at: Instant::now() });
+                println!("💱 [汇率预言机] 刷新成功，USD/CNY = {}", rate);
+            }
+            Err(e) => {
+                println!("⚠️ [汇率预言机] 刷新失败，沿用缓存/默认牌价: {}", e);
+            }
+        }
+    }
+
+    /// 启动后台周期刷新任务（`FX_RATE_REFRESH_SECS`，默认 300 秒一次）；未配置端点时任务仍会
+    /// 启动但每次都直接空转返回，成本可忽略
+    pub fn spawn_refresh_task(self: std::sync::Arc<Self>) {
+        let interval = std::env::var("FX_RATE_REFRESH_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_REFRESH_INTERVAL);
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                self.refresh_once().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 不走 `from_env`（会读环境变量、起 `reqwest::Client`），直接按测试需要的 ttl/默认值装配
+    fn test_oracle(ttl: Duration, default_rate: &str) -> FxRateOracle {
+        FxRateOracle {
+            http_client: reqwest::Client::new(),
+            endpoint: None,
+            ttl,
+            default_rate: Decimal::from_str(default_rate).unwrap(),
+            cached: Mutex::new(None),
+            manual_override: Mutex::new(None),
+        }
+    }
+
+    #[test]
+    fn fresh_cache_wins_over_default_rate() {
+        let oracle = test_oracle(Duration::from_secs(3600), "7.2");
+        *oracle.cached.lock().unwrap() = Some(CachedRate {
+            rate_usd_cny: Decimal::from_str("7.5").unwrap(),
+            fetched_at: Instant::now(),
+        });
+
+        assert_eq!(oracle.current_rate_usd_cny(), Decimal::from_str("7.5").unwrap());
+    }
+
+    #[test]
+    fn expired_cache_falls_back_to_default_rate() {
+        let oracle = test_oracle(Duration::from_millis(10), "7.2");
+        *oracle.cached.lock().unwrap() = Some(CachedRate {
+            rate_usd_cny: Decimal::from_str("7.5").unwrap(),
+            fetched_at: Instant::now() - Duration::from_secs(1),
+        });
+
+        assert_eq!(oracle.current_rate_usd_cny(), Decimal::from_str("7.2").unwrap());
+    }
+
+    #[test]
+    fn manual_override_wins_over_fresh_cache_and_default() {
+        let oracle = test_oracle(Duration::from_secs(3600), "7.2");
+        *oracle.cached.lock().unwrap() = Some(CachedRate {
+            rate_usd_cny: Decimal::from_str("7.5").unwrap(),
+            fetched_at: Instant::now(),
+        });
+        oracle.set_manual_override(Some(Decimal::from_str("6.9").unwrap()));
+
+        assert_eq!(oracle.current_rate_usd_cny(), Decimal::from_str("6.9").unwrap());
+    }
+
+    #[test]
+    fn clearing_manual_override_restores_cache_or_default() {
+        let oracle = test_oracle(Duration::from_secs(3600), "7.2");
+        oracle.set_manual_override(Some(Decimal::from_str("6.9").unwrap()));
+        assert_eq!(oracle.current_rate_usd_cny(), Decimal::from_str("6.9").unwrap());
+
+        oracle.set_manual_override(None);
+        assert_eq!(oracle.current_rate_usd_cny(), Decimal::from_str("7.2").unwrap());
+    }
+
+    #[test]
+    fn convert_uses_expired_cache_fallback_through_public_api() {
+        let oracle = test_oracle(Duration::from_millis(10), "7.2");
+        *oracle.cached.lock().unwrap() = Some(CachedRate {
+            rate_usd_cny: Decimal::from_str("100").unwrap(),
+            fetched_at: Instant::now() - Duration::from_secs(1),
+        });
+
+        let converted = oracle.convert(Decimal::from_str("1").unwrap(), Currency::Usd, Currency::Cny);
+        assert_eq!(converted, Decimal::from_str("7.2").unwrap());
+    }
+}
@@ -1,6 +1,7 @@
 use axum::{
-    extract::{State, Path as AxumPath, WebSocketUpgrade},
+    extract::{State, Path as AxumPath, Query, WebSocketUpgrade},
       extract::ws::{Message, WebSocket},
+    http::HeaderMap,
     response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
@@ -10,24 +11,69 @@ use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Notify};
 use dotenv::dotenv;
 use tiktoken_rs::cl100k_base;
+use rust_decimal::prelude::*;
 
+mod alerts;
+mod audit;
+mod billing_bus;
+mod budget;
+mod burn_rate;
 mod client;
+mod crypto;
+mod fx_oracle;
+mod metrics;
+mod redis_pool;
+mod storage;
+mod throttle;
 mod types;
 
+use alerts::AlertEngine;
+use audit::{AuditRecord, AuditSink};
+use billing_bus::BillingBus;
+use budget::TenantLedger;
+use burn_rate::BurnRateTracker;
 use client::Client;
+use metrics::Metrics;
 
 // ✅ [核心修正 1] 定义必须与初始化完全一致
 struct AppState {
     client: Arc<Client>,
     ws_tx: broadcast::Sender<Value>,
-    price_cache: Arc<Mutex<HashMap<String, types::PriceInfo>>>,
-    total_cost: Arc<AtomicU64>,
-    budget_limit: Arc<Mutex<f64>>, // 🆕 新增：熔断警戒线
+    price_cache: Arc<Mutex<HashMap<String, types::ModelPricing>>>,
+    // 💰 [多租户熔断] 按 API key / session_id 分账的预算账本（含全局汇总）
+    ledger: Arc<TenantLedger>,
     // 🆕 [性能优化] 全局复用 Tiktoken 编码器，避免重复加载
     bpe: Arc<tiktoken_rs::CoreBPE>,
+    // 📊 [可观测性] Prometheus 指标注册表
+    metrics: Arc<Metrics>,
+    // 🩺 [连接监护] Redis 是否健康；不健康时各 handler 应当降级而不是阻塞
+    redis_healthy: Arc<AtomicBool>,
+    // 🧾 [审计导出] 异步推送到 ES 兼容 `_bulk` 端点的审计日志
+    audit: Arc<AuditSink>,
+    // 🛑 [优雅停机] 正在排空中的标志 + 还在飞行中的流式请求计数；`shutdown_notify` 在标志翻转时
+    // 和每个在飞流式请求结束时都会被唤醒一次，排空循环靠它及时醒来，而不是定期轮询猜
+    shutting_down: Arc<AtomicBool>,
+    active_streams: Arc<AtomicUsize>,
+    shutdown_notify: Arc<Notify>,
+    // 📊 [烧钱速率告警] 滑动窗口烧钱速率 + 80% 预警 + 可配置 webhook
+    alerts: Arc<AlertEngine>,
+    // 📡 [跨实例计费总线] 可选 NATS/JetStream 后端，支持多实例聚合与历史重放
+    billing_bus: Arc<BillingBus>,
+    // 📊 [烧钱速率预测] 固定容量环形缓冲区，外推全局预算耗尽时间
+    burn_rate: Arc<BurnRateTracker>,
+}
+
+/// 🛑 [优雅停机] 流式响应体被 drop（正常结束或连接断开）时自动递减计数，并唤醒排空循环
+struct StreamGuard(Arc<AtomicUsize>, Arc<Notify>);
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        self.1.notify_waiters();
+    }
 }
 
 #[tokio::main]
@@ -38,6 +84,9 @@ async fn main() {
     let client = Client::create_default_client();
     let shared_client = Arc::new(client);
 
+    // 💱 [汇率预言机] 启动后台周期刷新（未配置 FX_RATE_ENDPOINT 时任务仍启动但每次空转）
+    shared_client.fx_oracle.clone().spawn_refresh_task();
+
     // 2. 异步启动 Redis 并等待连接成功
     let client_for_redis = shared_client.clone();
     tokio::spawn(async move {
@@ -45,6 +94,26 @@ async fn main() {
     });
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await; // 等待 Redis 连接
 
+    // 🩺 [连接监护] 后台任务周期性 PING Redis，失败时自动重连并翻转健康标志
+    let redis_healthy = Arc::new(AtomicBool::new(false));
+    let client_for_supervisor = shared_client.clone();
+    let redis_healthy_for_supervisor = redis_healthy.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            let healthy = client_for_supervisor.ping_redis().await;
+            if !healthy {
+                println!("⚠️ [Redis监护] PING 失败，尝试重新建立连接...");
+                if let Err(e) = client_for_supervisor.reconnect_redis().await {
+                    println!("❌ [Redis监护] 重连失败: {}", e);
+                }
+            }
+            let now_healthy = client_for_supervisor.ping_redis().await;
+            redis_healthy_for_supervisor.store(now_healthy, std::sync::atomic::Ordering::Relaxed);
+        }
+    });
+
     // 3. 启动价格同步定时任务（24 小时一次）
     let client_for_sync = shared_client.clone();
     tokio::spawn(async move {
@@ -82,8 +151,14 @@ async fn main() {
     let client_for_cache = shared_client.clone();
     let price_cache = Arc::new(Mutex::new(initial_prices));
     let cache_for_task = price_cache.clone();
+    let redis_healthy_for_cache = redis_healthy.clone();
     tokio::spawn(async move {
         loop {
+            // 🩺 [降级] Redis 不健康时跳过本轮刷新，继续使用内存里的旧缓存
+            if !redis_healthy_for_cache.load(std::sync::atomic::Ordering::Relaxed) {
+                tokio::time::sleep(tokio::time::Duration::from_secs(3600)).await;
+                continue;
+            }
             if let Ok(prices) = client_for_cache.get_all_prices_from_redis().await {
                 let mut guard = cache_for_task.lock().unwrap();
                 *guard = prices;
@@ -95,20 +170,103 @@ async fn main() {
 
     // 7. 准备所有零件
     let (tx, _) = broadcast::channel(100);
-    let total_cost = Arc::new(AtomicU64::new(0));
-    let budget_limit = Arc::new(Mutex::new(10.0)); // 默认熔断值：10元
-    
+    let ledger = Arc::new(TenantLedger::new(10.0)); // 默认熔断值：10元/美元（按租户独立覆盖）
+
     // 🆕 [性能优化] 初始化 Tiktoken 编码器（全局复用，避免重复加载）
     let bpe = Arc::new(cl100k_base().unwrap());
 
-    // ✅ [核心修正 2] 初始化 AppState，确保不多不少，正好这六个字段
+    // 📊 [可观测性] 初始化指标注册表
+    let metrics = Arc::new(Metrics::new());
+
+    // 🧾 [审计导出] 从环境变量装配审计日志后台任务
+    let audit = Arc::new(AuditSink::from_env());
+
+    // 🛑 [优雅停机] 排空状态与在飞请求计数
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let active_streams = Arc::new(AtomicUsize::new(0));
+    let shutdown_notify = Arc::new(Notify::new());
+
+    // 📊 [烧钱速率告警] 滑动窗口 + EWMA 引擎，默认 80% 预警 / 3 倍突增
+    let alerts = Arc::new(AlertEngine::new());
+
+    // 📡 [跨实例计费总线] 未配置 NATS_URL 时自动退化为单实例模式
+    let billing_bus = Arc::new(BillingBus::from_env().await);
+
+    // 📡 [跨实例计费总线] 订阅其它实例发布的计费增量，汇入本地账本，让熔断判断基于跨实例真实总花费
+    if billing_bus.is_enabled() {
+        let billing_bus_for_sub = billing_bus.clone();
+        let ledger_for_sub = ledger.clone();
+        tokio::spawn(async move {
+            let Some(mut sub) = billing_bus_for_sub.subscribe().await else { return };
+            while let Some(msg) = sub.next().await {
+                let Ok(event) = serde_json::from_slice::<Value>(&msg.payload) else { continue };
+                let Some(origin) = event.get("instance_id").and_then(|v| v.as_str()) else { continue };
+                if origin == billing_bus_for_sub.instance_id {
+                    continue; // 跳过自己发布给自己的增量，避免重复计费
+                }
+                let Some(client_id) = event.get("client_id").and_then(|v| v.as_str()) else { continue };
+                let Some(delta_cost) = event.get("delta_cost").and_then(|v| v.as_f64()) else { continue };
+                ledger_for_sub.get_or_create(client_id).add_cost(delta_cost);
+                ledger_for_sub.global_spend_fixed.fetch_add(
+                    (delta_cost * 1_000_000_000_000.0) as u64,
+                    std::sync::atomic::Ordering::Relaxed,
+                );
+            }
+        });
+    }
+
+    // 📊 [烧钱速率预测] 周期性采样全局累计成本，外推预算耗尽时间
+    let burn_rate = Arc::new(BurnRateTracker::new());
+    {
+        let burn_rate_for_task = burn_rate.clone();
+        let ledger_for_burn_rate = ledger.clone();
+        let ws_tx_for_burn_rate = tx.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(burn_rate::SAMPLE_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let current_cost = ledger_for_burn_rate.global_spend();
+                let budget_limit = *ledger_for_burn_rate.default_limit.lock().unwrap();
+                let reading = burn_rate_for_task.sample(current_cost, budget_limit);
+
+                let _ = ws_tx_for_burn_rate.send(json!({
+                    "type": "cost_rate",
+                    "cost": current_cost,
+                    "rate_per_sec": reading.rate_per_sec,
+                }));
+
+                if burn_rate_for_task.should_alarm(&reading) {
+                    println!(
+                        "🔥 [烧钱速率告警] 当前速率 {:.6}/s，预计 {:.0}s 后耗尽预算",
+                        reading.rate_per_sec,
+                        reading.eta_seconds.unwrap_or(0.0)
+                    );
+                    let _ = ws_tx_for_burn_rate.send(json!({
+                        "type": "burn_rate_alarm",
+                        "rate": reading.rate_per_sec,
+                        "eta_seconds": reading.eta_seconds,
+                    }));
+                }
+            }
+        });
+    }
+
+    // ✅ [核心修正 2] 初始化 AppState
     let app_state = Arc::new(AppState {
         client: shared_client,
         ws_tx: tx,
         price_cache,
-        total_cost,
-        budget_limit,
+        ledger,
         bpe,
+        metrics,
+        redis_healthy,
+        audit,
+        shutting_down,
+        active_streams,
+        shutdown_notify,
+        alerts,
+        billing_bus,
+        burn_rate,
     });
 
     // 6. 构建路由：使用 nest 确保 /v1 前缀绝对生效
@@ -117,14 +275,23 @@ async fn main() {
         .route("/chat/completions", post(chat_handler))
         .route("/config/limit", post(update_limit))
         .route("/config/reset_cost", post(reset_cost))
+        .route("/config/alerts", post(update_alert_config))
+        .route("/clients", get(list_clients))
+        .route("/clients/:client_id/cost", get(get_client_cost))
         .route("/status", get(get_status))
         .route("/check_gate", get(check_gate))
         .route("/admin/refresh_prices", get(refresh_prices))
+        .route("/admin/verify_price_integrity", get(verify_price_integrity_handler))
+        .route("/billing/replay", get(billing_replay))
+        .route("/analytics", get(get_analytics))
         .route("/ws", get(ws_handler)); // ✅ 将 WebSocket 也移到 /v1 命名空间内
 
+    let state_for_shutdown = app_state.clone();
+
     let app = Router::new()
         .route("/status", get(get_status))
         .route("/check_gate", get(check_gate))
+        .route("/metrics", get(metrics_handler)) // 📊 Prometheus 抓取端点
         .nest("/v1", api_routes) // ✅ 使用 nest 确保 /v1 前缀绝对生效
         .with_state(app_state);
 
@@ -132,7 +299,7 @@ async fn main() {
     let addr = "127.0.0.1:3001";
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     println!("🚀 [Sentinel] 哨兵核心已就位: http://{}", addr);
-    
+
     // 🆕 [优雅停机] 捕获 Ctrl+C 信号
     let ctrl_c = async {
         tokio::signal::ctrl_c()
@@ -140,11 +307,47 @@ async fn main() {
             .expect("Failed to install CTRL+C handler");
         println!("\n🛑 [Sentinel] 收到 Ctrl+C 信号，准备优雅停机...");
     };
-    
+
     tokio::select! {
         _ = ctrl_c => {
             println!("🛑 [Sentinel] 开始优雅停机...");
-            // 这里可以添加清理逻辑，比如关闭 Redis 连接等
+            // 🛑 [优雅停机] 停止接受新请求语义上由不再 accept 新连接体现；
+            // 这里先翻转标志，再等待在飞流式请求排空（带超时），最后落盘累计成本
+            state_for_shutdown.shutting_down.store(true, std::sync::atomic::Ordering::SeqCst);
+            // 🛑 [优雅停机] 唤醒一次：只会叫醒正在等待 shutdown_notify 的任务；在飞流式请求
+            // 在处理下一个 chunk 时才会看到 shutting_down 并提前中断
+            state_for_shutdown.shutdown_notify.notify_waiters();
+
+            let drain_deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(15);
+            loop {
+                let remaining = state_for_shutdown.active_streams.load(std::sync::atomic::Ordering::SeqCst);
+                if remaining == 0 {
+                    println!("✅ [Sentinel] 所有在飞流式请求已排空");
+                    break;
+                }
+
+                let Some(timeout) = drain_deadline.checked_duration_since(tokio::time::Instant::now()) else {
+                    println!("⚠️ [Sentinel] 排空超时，仍有 {} 个流式请求未结束，强制退出", remaining);
+                    break;
+                };
+
+                // 🛑 [优雅停机] 每个在飞流结束时 StreamGuard::drop 都会 notify_waiters 一次，
+                // 这里醒来就重新检查 active_streams，取代原来固定 200ms 的轮询
+                tokio::select! {
+                    _ = state_for_shutdown.shutdown_notify.notified() => {}
+                    _ = tokio::time::sleep(timeout) => {
+                        println!("⚠️ [Sentinel] 排空超时，仍有 {} 个流式请求未结束，强制退出", state_for_shutdown.active_streams.load(std::sync::atomic::Ordering::SeqCst));
+                        break;
+                    }
+                }
+            }
+
+            if let Err(e) = state_for_shutdown.client.save_cumulative_cost_to_redis(state_for_shutdown.ledger.global_spend()).await {
+                println!("⚠️ [Sentinel] 落盘累计成本失败: {}", e);
+            } else {
+                println!("💾 [Sentinel] 累计成本已落盘到 Redis");
+            }
+
             println!("✅ [Sentinel] 优雅停机完成");
             std::process::exit(0);
         }
@@ -156,43 +359,66 @@ async fn main() {
 
 // --- Handler 逻辑 ---
 
-// ✅ 哨兵状态查询接口：获取当前费用和限额（单位统一为元）
+// ✅ 哨兵状态查询接口：获取当前调用方（按 Authorization / session_id 分账）的费用和限额
 #[axum::debug_handler]
 async fn get_status(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let current = state.total_cost.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000_000_000.0;
-    let limit = *state.budget_limit.lock().unwrap();
-    
+    let tenant_id = budget::resolve_tenant_id(&headers, "default");
+    let tenant = state.ledger.get_or_create(&tenant_id);
+
     Json(json!({
-        "total_cost": current,
-        "limit": limit
+        "tenant_id": tenant_id,
+        "total_cost": tenant.spend(),
+        "limit": tenant.limit(),
+        "global_total_cost": state.ledger.global_spend(),
+        "redis_healthy": state.redis_healthy.load(std::sync::atomic::Ordering::Relaxed)
     }))
 }
 
-// ✅ 哨兵预检接口：让前端"预检"是否允许发送请求（单位统一为元）
+// ✅ 哨兵预检接口：让前端"预检"是否允许发送请求（按租户分账）
 #[axum::debug_handler]
 async fn check_gate(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let current = state.total_cost.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000_000_000.0;
-    let limit = *state.budget_limit.lock().unwrap();
-    
-    let allowed = current < limit;
-    
+    let tenant_id = budget::resolve_tenant_id(&headers, "default");
+    let tenant = state.ledger.get_or_create(&tenant_id);
+    let allowed = !tenant.over_budget();
+
     Json(json!({
+        "tenant_id": tenant_id,
         "allowed": allowed,
-        "current_cost": current,
-        "limit": limit
+        "current_cost": tenant.spend(),
+        "limit": tenant.limit()
     }))
 }
 
+// 📊 Prometheus 抓取端点：输出计数器/直方图的文本格式
+#[axum::debug_handler]
+async fn metrics_handler(
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let default_limit = *state.ledger.default_limit.lock().unwrap();
+    (
+        [("Content-Type", "text/plain; version=0.0.4")],
+        state.metrics.render(default_limit),
+    )
+}
+
 // ✅ 使用 impl IntoResponse 是解决所有 E0277 的终极良药
 #[axum::debug_handler]
 async fn chat_handler(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(mut payload): Json<Value>,
 ) -> Result<Response, axum::http::StatusCode> {
+    // 🛑 [优雅停机] 正在排空中时不再接受新请求，避免排空窗口内又冒出新的在飞流
+    if state.shutting_down.load(std::sync::atomic::Ordering::SeqCst) {
+        return Err(axum::http::StatusCode::SERVICE_UNAVAILABLE);
+    }
+
     // ✅ 第一时间打印请求信息，避免静默失败
     println!("📨 [DEBUG] 收到新请求");
     if let Some(model) = payload.get("model").and_then(|m| m.as_str()) {
@@ -212,21 +438,40 @@ async fn chat_handler(
     let simplified_model = state.client.simplify_model_id(&model);
     println!("🔍 [DEBUG] 原始模型名: {}, 简化后: {}", model, simplified_model);
     
-    // 🆕 [累计熔断] 检查累计成本是否超过预算
-    let current_cost = state.total_cost.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000_000_000.0;
-    let budget_limit = *state.budget_limit.lock().unwrap();
-    
-    if current_cost >= budget_limit {
-        println!("🛡️ [累计熔断生效] 累计成本 ￥{:.4} 已达到预算限额 ￥{:.4}", current_cost, budget_limit);
-        return Ok((axum::http::StatusCode::PAYMENT_REQUIRED, 
-                 json!({"error": "预算已耗尽", "current_cost": current_cost, "limit": budget_limit}).to_string()).into_response());
+    // 💰 [多租户熔断] 按 Authorization / session_id 解析租户，再检查该租户自己的预算
+    let tenant_id = budget::resolve_tenant_id(&headers, &session_id);
+    let tenant_budget = state.ledger.get_or_create(&tenant_id);
+    let current_cost = tenant_budget.spend();
+    let budget_limit = tenant_budget.limit();
+
+    // 🛡️ [真熔断] 三态熔断器：Closed 超限则打开并拒绝；Open 冷却到期转 HalfOpen 放行一个探测请求；
+    // HalfOpen 期间的其它请求一律拒绝
+    let (admit_decision, transitioned) = tenant_budget.admit();
+    if transitioned {
+        broadcast_circuit_state(&state, &tenant_id, tenant_budget.circuit_state(), current_cost, budget_limit);
     }
-    
+    let is_probe_request = matches!(admit_decision, budget::CircuitDecision::AdmitProbe);
+    if let budget::CircuitDecision::Reject { retry_after_secs } = admit_decision {
+        println!("🛑 [熔断拦截] 租户 {} 处于 {:?}，拒绝本次请求", tenant_id, tenant_budget.circuit_state());
+        return Ok((axum::http::StatusCode::PAYMENT_REQUIRED,
+                 json!({
+                     "error": "预算熔断中",
+                     "tenant_id": tenant_id,
+                     "current_cost": current_cost,
+                     "limit": budget_limit,
+                     "retry_after_secs": retry_after_secs
+                 }).to_string()).into_response());
+    }
+    if is_probe_request {
+        println!("🩺 [熔断探测] 租户 {} 进入 HalfOpen，放行一个探测请求", tenant_id);
+    }
+
     // 🆕 [单次计费模式 1] 重置计费逻辑：初始化临时计数器
     let request_cost = Arc::new(AtomicU64::new(0));
     
     // C. 注入记忆（只有当 load_history 为 true 时才加载历史对话）
-    if load_history {
+    // 🩺 [降级] Redis 不健康时直接跳过记忆注入，而不是阻塞等待一次必然失败的调用
+    if load_history && state.redis_healthy.load(std::sync::atomic::Ordering::Relaxed) {
         let history = state.client.get_messages_from_redis(&session_id).await.unwrap_or_default();
         if let Some(messages) = payload.get_mut("messages").and_then(|m| m.as_array_mut()) {
             for (i, msg) in history.into_iter().enumerate() {
@@ -251,21 +496,43 @@ async fn chat_handler(
         }
     }
     
+    // 📊 [可观测性] 记录上游 chat_completion 的耗时
+    let upstream_started_at = std::time::Instant::now();
+    let metrics_for_handler = state.metrics.clone();
+
     match state.client.chat_completion(&model, payload.clone(), &session_id).await {
         Ok(resp) => {
+            let upstream_latency_ms = upstream_started_at.elapsed().as_secs_f64() * 1000.0;
+            metrics_for_handler.record_latency_ms(upstream_latency_ms);
             let status = resp.status().as_u16();
-            
+
+            // 🛡️ [真熔断] 探测请求的上游调用成功返回，即可闭合熔断器（仍需实际花费回落到限额以下）
+            if is_probe_request {
+                let probe_transitioned = tenant_budget.record_probe_result(true);
+                if probe_transitioned {
+                    broadcast_circuit_state(&state, &tenant_id, tenant_budget.circuit_state(), tenant_budget.spend(), tenant_budget.limit());
+                }
+            }
+
             // ✅ 检查是否为流式响应
             let is_stream = payload.get("stream").and_then(|v| v.as_bool()).unwrap_or(false);
             
             if is_stream {
                 // 流式模式处理
                 let stream = resp.bytes_stream();
-                let _user_limit = *state.budget_limit.lock().unwrap();
                 let model_for_cost = model.clone();
                 let price_cache_for_cost = state.price_cache.lock().unwrap().clone();
                 let state_for_billing = state.clone();
+                let tenant_budget_for_stream = tenant_budget.clone();
+                let tenant_id_for_stream = tenant_id.clone();
                 let request_cost_for_ws = request_cost.clone();
+                let metrics_for_stream = state.metrics.clone();
+                let audit_for_stream = state.audit.clone();
+                let session_id_for_audit = session_id.clone();
+
+                // 🛑 [优雅停机] 登记一个在飞流式请求；guard 在响应体被 drop 时自动注销
+                state.active_streams.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let stream_guard = StreamGuard(state.active_streams.clone(), state.shutdown_notify.clone());
                 
                 // 🆕 [异步旁路] 准备异步保存消息到 Redis
                 let client_for_redis = state.client.clone();
@@ -293,9 +560,17 @@ async fn chat_handler(
                 let last_emitted_cost_clone = last_emitted_cost.clone();
 
             let mapped_stream = stream.map(move |item| {
+                // 🛑 [优雅停机] 仅为了把 guard 的生命周期绑定到这个闭包（也就是整条流）上
+                let _keep_stream_guard_alive = &stream_guard;
+
                 if is_fused_clone.load(std::sync::atomic::Ordering::Relaxed) {
                     return Err(anyhow::anyhow!("Budget limit exceeded"));
                 }
+
+                // 🛑 [优雅停机] 排空期间主动中断在飞流，不再等它自然结束，配合排空循环尽快收尾
+                if state_for_billing.shutting_down.load(std::sync::atomic::Ordering::SeqCst) {
+                    return Err(anyhow::anyhow!("Server is shutting down"));
+                }
                 
                 match item {
                     Ok(chunk) => {
@@ -303,7 +578,10 @@ async fn chat_handler(
                         let chunk_str = std::str::from_utf8(&chunk).unwrap_or("");
                         
                         // 解析 SSE 格式：data: {...}\n\n
-                        let json_opt = chunk_str
+                        // 🐛 [修复] 一次 TCP 读取里可能带多条 `data:` 事件（上游合并发送、或者
+                        // 读取节奏落后于产出节奏），只取 `.next()` 的第一条会悄悄丢掉同一个
+                        // chunk 里后面的增量 / usage 事件；这里改成逐条处理整个 chunk 里的事件
+                        let json_events: Vec<Value> = chunk_str
                             .lines()
                             .filter(|line| line.starts_with("data: "))
                             .filter_map(|line| {
@@ -314,9 +592,9 @@ async fn chat_handler(
                                     serde_json::from_str::<Value>(json_str).ok()
                                 }
                             })
-                            .next();
-                        
-                        if let Some(json) = json_opt {
+                            .collect();
+
+                        for json in json_events {
                             // 实时提取并计数 completion tokens
                             if let Some(choices) = json.get("choices").and_then(|c| c.as_array()) {
                                 if let Some(delta) = choices.first().and_then(|c| c.get("delta")) {
@@ -329,39 +607,65 @@ async fn chat_handler(
                                         let total_tokens = token_emit_counter_clone.fetch_add(token_count, std::sync::atomic::Ordering::Relaxed) + token_count;
                                         
                                         // 🆕 [实时跳钱] 使用 tiktoken 精确计算成本
-                                        let (estimated_chunk_cost, currency) = types::calculate_real_time_cost(
+                                        let (estimated_chunk_cost_decimal, currency) = types::calculate_real_time_cost(
                                             &json,
                                             &model_for_cost,
                                             &price_cache_for_cost,
-                                            &bpe
+                                            &bpe,
+                                            &state_for_billing.client.currency_resolver,
+                                            &state_for_billing.client.fx_oracle
                                         );
-                                        
-                                        let cost_in_cents = (estimated_chunk_cost * 1_000_000_000_000.0) as u64;
-                                        state_for_billing.total_cost.fetch_add(cost_in_cents, std::sync::atomic::Ordering::SeqCst);
-                                        
-                                        let _currency_symbol = if currency == "USD" { "$" } else { "￥" };
+                                        // 💰 [精确计费] 计费核心用 Decimal 精确算完，落入既有 f64 定点账本前才转换一次
+                                        let estimated_chunk_cost = estimated_chunk_cost_decimal.to_f64().unwrap_or(0.0);
+
+                                        tenant_budget_for_stream.add_cost(estimated_chunk_cost);
+                                        state_for_billing.ledger.global_spend_fixed.fetch_add(
+                                            (estimated_chunk_cost * 1_000_000_000_000.0) as u64,
+                                            std::sync::atomic::Ordering::Relaxed,
+                                        );
+
+                                        // 📡 [跨实例计费总线] 把这次扣费的增量发布给其它实例，让熔断判断基于跨实例真实总花费
+                                        if estimated_chunk_cost > 0.0 {
+                                            let bus_for_publish = state_for_billing.billing_bus.clone();
+                                            let delta_event = json!({
+                                                "instance_id": bus_for_publish.instance_id,
+                                                "client_id": tenant_id_for_stream,
+                                                "delta_cost": estimated_chunk_cost,
+                                            });
+                                            tokio::spawn(async move {
+                                                bus_for_publish.publish(&delta_event).await;
+                                            });
+                                        }
+
+                                        let _currency_symbol = if currency == types::Currency::Usd { "$" } else { "￥" };
                                         println!("🔍 [DEBUG] 实时计数: 新增 {} tokens, 累计 {} tokens", token_count, completion_tokens_clone.load(std::sync::atomic::Ordering::Relaxed));
-                                        println!("💰 [DEBUG] 实时计费: 本次估算 {}{:.9}, 累计 {}{:.6}", _currency_symbol, estimated_chunk_cost, _currency_symbol, state_for_billing.total_cost.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000_000_000.0);
-                                        
-                                        // 🆕 [流式熔断] 检查是否超过预算
-                                        let current_total = state_for_billing.total_cost.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000_000_000.0;
-                                        let budget_limit = *state_for_billing.budget_limit.lock().unwrap();
-                                        
+                                        println!("💰 [DEBUG] 实时计费: 本次估算 {}{:.9}, 累计 {}{:.6}", _currency_symbol, estimated_chunk_cost, _currency_symbol, tenant_budget_for_stream.spend());
+
+                                        // 🆕 [流式熔断] 检查是否超过预算（按租户）
+                                        let current_total = tenant_budget_for_stream.spend();
+                                        let budget_limit = tenant_budget_for_stream.limit();
+
                                         if current_total >= budget_limit {
                                             println!("🛡️ [流式熔断生效] 累计成本 {}{:.4} 已达到预算限额 {}{:.4}", _currency_symbol, current_total, _currency_symbol, budget_limit);
-                                            
-                                            // 🆕 [铁血熔断] 设置熔断标志，立即中断流
+
+                                            // 🆕 [铁血熔断] 设置本次流的中断标志，不再发送后续数据
                                             is_fused.store(true, std::sync::atomic::Ordering::SeqCst);
-                                            
-                                            // 🆕 [熔断处理] 发送熔断消息给灵动岛（确保立即发送）
-                                            let fuse_msg = json!({
-                                                "type": "billing",
-                                                "model": model_for_cost.clone(),
-                                                "cost": current_total,
-                                                "currency": currency,
-                                                "fused": true
-                                            });
-                                            
+
+                                            // 🛡️ [真熔断] 打开租户的熔断器；只有真正发生迁移才广播，避免同一个流里每个 chunk 都刷一遍
+                                            let just_tripped = tenant_budget_for_stream.trip();
+                                            if just_tripped {
+                                                let circuit_msg = json!({
+                                                    "type": "circuit_break",
+                                                    "tenant_id": tenant_id_for_stream,
+                                                    "state": "open",
+                                                    "cost": current_total,
+                                                    "limit": budget_limit,
+                                                });
+                                                if let Err(e) = state_for_billing.ws_tx.send(circuit_msg) {
+                                                    println!("❌ [DEBUG] 熔断消息发送失败: {}", e);
+                                                }
+                                            }
+
                                             // 🆕 [熔断处理] 发送错误信号（确保前端立即响应）
                                             let error_msg = json!({
                                                 "type": "error",
@@ -369,18 +673,10 @@ async fn chat_handler(
                                                 "cost": current_total,
                                                 "currency": currency
                                             });
-                                            
-                                            // 确保两个消息都发送成功
-                                            let fuse_result = state_for_billing.ws_tx.send(fuse_msg);
-                                            let error_result = state_for_billing.ws_tx.send(error_msg);
-                                            
-                                            if let Err(e) = fuse_result {
-                                                println!("❌ [DEBUG] 熔断消息发送失败: {}", e);
-                                            }
-                                            if let Err(e) = error_result {
+                                            if let Err(e) = state_for_billing.ws_tx.send(error_msg) {
                                                 println!("❌ [DEBUG] 错误信号发送失败: {}", e);
                                             }
-                                            
+
                                             // 🆕 [铁血熔断] 立即中断连接，不再发送后续数据
                                             return Err(anyhow::anyhow!("Budget limit exceeded"));
                                         }
@@ -409,16 +705,28 @@ async fn chat_handler(
                                             if should_send_by_tokens || should_send_by_cost || should_send_by_time {
                                                 let billing_msg = json!({
                                                     "type": "billing",
+                                                    "client_id": tenant_id_for_stream,
+                                                    "session_id": session_id_for_audit.clone(),
                                                     "model": model_for_cost.clone(),
                                                     "cost": current_total,
                                                     "currency": currency
                                                 });
                                                 
+                                                // 📡 [跨实例计费总线] 同一条消息也落盘到 JetStream，供断线重连的仪表盘重放
+                                                let billing_msg_for_bus = billing_msg.clone();
+                                                let bus_for_replay = state_for_billing.billing_bus.clone();
+                                                tokio::spawn(async move {
+                                                    bus_for_replay.publish(&billing_msg_for_bus).await;
+                                                });
+
                                                 match state_for_billing.ws_tx.send(billing_msg) {
                                                     Ok(_) => {},
                                                     Err(e) => println!("❌ [DEBUG] billing 消息发送失败: {}", e),
                                                 }
-                                                
+
+                                                // 📊 [烧钱速率告警] 与 billing 消息同一节流节奏，采样一次烧钱速率
+                                                dispatch_alerts(&state_for_billing, &tenant_id_for_stream, current_total, budget_limit, &model_for_cost);
+
                                                 // 更新上次发送金额
                                                 *last_emitted_cost_clone.lock().unwrap() = current_total;
                                                 
@@ -434,7 +742,7 @@ async fn chat_handler(
                             if let Some(usage) = json.get("usage") {
                                 println!("🔍 [DEBUG] 检测到最后一个 chunk，包含 usage: {}", usage);
                                 
-                                let usage_struct: types::Usage = match serde_json::from_value(usage.clone()) {
+                                let mut usage_struct: types::Usage = match serde_json::from_value(usage.clone()) {
                                     Ok(u) => u,
                                     Err(e) => {
                                         println!("⚠️ [DEBUG] 解析 usage 失败: {}", e);
@@ -442,17 +750,65 @@ async fn chat_handler(
                                     }
                                 };
                                 
-                                // 使用官方的 prompt_tokens 和实时计数的 completion_tokens
-                                let prompt_tokens = usage_struct.prompt_tokens.unwrap_or(0) as f64;
-                                let real_completion_tokens = completion_tokens.load(std::sync::atomic::Ordering::Relaxed) as f64;
-                                
-                                let (actual_cost, currency) = types::calculate_actual_cost_with_tokens(&model_for_cost, prompt_tokens, real_completion_tokens, &price_cache_for_cost);
-                                
+                                // 使用官方的 prompt_tokens；completion_tokens 优先采用服务端上报值，
+                                // 上报缺失时兜底到实时计数，和 BillingSummary 内部的兜底规则保持一致
+                                let real_completion_tokens = completion_tokens.load(std::sync::atomic::Ordering::Relaxed) as u64;
+                                if usage_struct.completion_tokens.is_none() {
+                                    usage_struct.completion_tokens = Some(real_completion_tokens);
+                                }
+
+                                // 🆕 [流式计费汇总] 用权威 usage 重算出一份干净的端到端 BillingSummary，
+                                // 供指标、审计和 WS 计费消息读取；租户账本不在这里重算，保留逐 chunk 的估算累计
+                                let mut stream_cost_acc = types::StreamCostAccumulator::new(
+                                    &model_for_cost,
+                                    &price_cache_for_cost,
+                                    &state_for_billing.client.currency_resolver,
+                                    &state_for_billing.client.fx_oracle,
+                                    &bpe,
+                                );
+                                stream_cost_acc.reconcile_with_usage(&usage_struct);
+                                let billing_summary = stream_cost_acc.finish();
+
+                                let prompt_tokens = billing_summary.input_tokens;
+                                let real_completion_tokens = billing_summary.output_tokens;
+                                let actual_cost_decimal = billing_summary.cost;
+                                let currency = billing_summary.currency;
+                                // 💰 [精确计费] 计费核心用 Decimal 精确算完，落入既有 f64 账本前才转换一次
+                                let actual_cost = actual_cost_decimal.to_f64().unwrap_or(0.0);
+
+                                // 📊 [可观测性] 记录 token/成本指标（流式分支）
+                                metrics_for_stream.record_tokens(prompt_tokens, real_completion_tokens);
+                                metrics_for_stream.record_cost(actual_cost);
+
+                                // 🧾 [审计导出] 流式请求结束，推一条完整的计费审计记录
+                                // 🐛 [修复] simplified_model 要用和非流式分支一致的归一化方式，
+                                // 不能直接拿原始 model_for_cost 充数，否则审计/分析记录里丢失归一化名
+                                let simplified_model_for_audit = model_for_cost.to_lowercase().trim().to_string();
+                                audit_for_stream.record(AuditRecord {
+                                    ts: std::time::SystemTime::now()
+                                        .duration_since(std::time::UNIX_EPOCH)
+                                        .map(|d| d.as_secs())
+                                        .unwrap_or(0),
+                                    session_id: session_id_for_audit.clone(),
+                                    model: model_for_cost.clone(),
+                                    simplified_model: simplified_model_for_audit,
+                                    prompt_tokens,
+                                    completion_tokens: real_completion_tokens,
+                                    cost: actual_cost,
+                                    currency: currency.to_string(),
+                                    stream: true,
+                                    fused: is_fused_clone.load(std::sync::atomic::Ordering::Relaxed),
+                                    upstream_latency_ms,
+                                    upstream_status: status,
+                                });
+
                                 if actual_cost > 0.0 {
-                                    let currency_symbol = if currency == "USD" { "$" } else { "￥" };
+                                    let currency_symbol = if currency == types::Currency::Usd { "$" } else { "￥" };
                                     
                                     let billing_msg = json!({
                                         "type": "billing",
+                                        "client_id": tenant_id_for_stream,
+                                        "session_id": session_id_for_audit.clone(),
                                         "model": model_for_cost,
                                         "cost": actual_cost,
                                         "currency": currency
@@ -460,7 +816,14 @@ async fn chat_handler(
                                     
                                     println!("🔍 [DEBUG] 流式模式最终 billing 消息: {}", billing_msg);
                                     println!("💰 [WebSocket] 广播计费: {} = {}{:.9}", model_for_cost, currency_symbol, actual_cost);
-                                    
+
+                                    // 📡 [跨实例计费总线] 同一条消息也落盘到 JetStream，供断线重连的仪表盘重放
+                                    let billing_msg_for_bus = billing_msg.clone();
+                                    let bus_for_replay = state_for_billing.billing_bus.clone();
+                                    tokio::spawn(async move {
+                                        bus_for_replay.publish(&billing_msg_for_bus).await;
+                                    });
+
                                     // 🆕 [单次计费模式 3] 同步更新：立即通过 WebSocket 发送给灵动岛
                                     match state_for_billing.ws_tx.send(billing_msg) {
                                         Ok(_) => println!("✅ [DEBUG] billing 消息发送成功"),
@@ -556,14 +919,63 @@ async fn chat_handler(
                 if let Some(usage) = response_json.get("usage") {
                     let simplified_model = model.to_lowercase().trim().to_string();
                     let usage_struct: types::Usage = serde_json::from_value(usage.clone()).map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)?;
-                    let (actual_cost, currency) = types::calculate_actual_cost(&simplified_model, &usage_struct, &state.price_cache.lock().unwrap());
-                    
+                    let (actual_cost_decimal, currency) = types::calculate_actual_cost(&simplified_model, &usage_struct, &state.price_cache.lock().unwrap(), &state.client.currency_resolver, &state.client.fx_oracle);
+                    // 💰 [精确计费] 计费核心用 Decimal 精确算完，落入既有 f64 账本前才转换一次
+                    let actual_cost = actual_cost_decimal.to_f64().unwrap_or(0.0);
+
+                    // 📊 [可观测性] 记录 token/成本指标（非流式分支）
+                    state.metrics.record_tokens(
+                        usage_struct.prompt_tokens.unwrap_or(0),
+                        usage_struct.completion_tokens.unwrap_or(0),
+                    );
+                    state.metrics.record_cost(actual_cost);
+
+                    // 🧾 [审计导出] 非流式请求同样推一条审计记录
+                    state.audit.record(AuditRecord {
+                        ts: std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0),
+                        session_id: session_id.clone(),
+                        model: model.clone(),
+                        simplified_model: simplified_model.clone(),
+                        prompt_tokens: usage_struct.prompt_tokens.unwrap_or(0),
+                        completion_tokens: usage_struct.completion_tokens.unwrap_or(0),
+                        cost: actual_cost,
+                        currency: currency.to_string(),
+                        stream: false,
+                        fused: false,
+                        upstream_latency_ms,
+                        upstream_status: status_code,
+                    });
+
                     if actual_cost > 0.0 {
-                        let currency_symbol = if currency == "USD" { "$" } else { "￥" };
-                        let current_total = state.total_cost.load(std::sync::atomic::Ordering::Relaxed) as f64 / 1_000_000_000_000.0;
-                        
+                        let currency_symbol = if currency == types::Currency::Usd { "$" } else { "￥" };
+                        tenant_budget.add_cost(actual_cost);
+                        state.ledger.global_spend_fixed.fetch_add(
+                            (actual_cost * 1_000_000_000_000.0) as u64,
+                            std::sync::atomic::Ordering::Relaxed,
+                        );
+
+                        // 📡 [跨实例计费总线] 非流式请求也同步发布扣费增量
+                        {
+                            let bus_for_publish = state.billing_bus.clone();
+                            let delta_event = json!({
+                                "instance_id": bus_for_publish.instance_id,
+                                "client_id": tenant_id,
+                                "delta_cost": actual_cost,
+                            });
+                            tokio::spawn(async move {
+                                bus_for_publish.publish(&delta_event).await;
+                            });
+                        }
+
+                        let current_total = tenant_budget.spend();
+
                         let billing_msg = json!({
                             "type": "billing",
+                            "client_id": tenant_id,
+                            "session_id": session_id.clone(),
                             "model": model,
                             "cost": current_total,
                             "currency": currency
@@ -571,13 +983,23 @@ async fn chat_handler(
                         
                         println!("🔍 [DEBUG] 非流模式发送 billing 消息: {}", billing_msg);
                         println!("💰 [WebSocket] 广播计费: {} = {}{:.9}", model, currency_symbol, current_total);
-                        
+
+                        // 📡 [跨实例计费总线] 同一条消息也落盘到 JetStream，供断线重连的仪表盘重放
+                        let billing_msg_for_bus = billing_msg.clone();
+                        let bus_for_replay = state.billing_bus.clone();
+                        tokio::spawn(async move {
+                            bus_for_replay.publish(&billing_msg_for_bus).await;
+                        });
+
                         // 🆕 [单次计费模式 3] 同步更新：立即通过 WebSocket 发送给灵动岛
                         match state.ws_tx.send(billing_msg) {
                             Ok(_) => println!("✅ [DEBUG] billing 消息发送成功"),
                             Err(e) => println!("❌ [DEBUG] billing 消息发送失败: {}", e),
                         }
-                        
+
+                        // 📊 [烧钱速率告警] 非流式请求也喂一次采样
+                        dispatch_alerts(&state, &tenant_id, current_total, budget_limit, &model);
+
                         // 更新临时计数器（以分为单位）
                         request_cost.fetch_add((actual_cost * 100.0) as u64, std::sync::atomic::Ordering::Relaxed);
                     } else {
@@ -595,65 +1017,276 @@ async fn chat_handler(
                     .unwrap())
             }
         }
-        Err(e) => Ok((axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()),
+        Err(e) => {
+            // 🛡️ [真熔断] 探测请求本身调用上游失败，视为探测失败，重新打开熔断器
+            if is_probe_request {
+                let probe_transitioned = tenant_budget.record_probe_result(false);
+                if probe_transitioned {
+                    broadcast_circuit_state(&state, &tenant_id, tenant_budget.circuit_state(), tenant_budget.spend(), tenant_budget.limit());
+                }
+            }
+            Ok((axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response())
+        }
+    }
+}
+
+/// 🆕 [WS 控制协议] `update_limit` 的核心逻辑，抽出来供 HTTP 端点与 WebSocket `set_limit` 命令共用
+async fn apply_set_limit(state: &Arc<AppState>, headers: &HeaderMap, payload: &Value) -> Result<Value, String> {
+    let Some(new_limit) = payload["limit"].as_f64() else {
+        return Err("无效的限额数值".to_string());
+    };
+    let currency_symbol = if state.client.currency_base == "USD" { "$" } else { "￥" };
+
+    // 没有携带 Authorization / session_id 时沿用旧行为：更新全局默认限额
+    let session_id = payload["session_id"].as_str().filter(|s| !s.is_empty());
+    if session_id.is_some() || headers.contains_key("Authorization") {
+        let tenant_id = budget::resolve_tenant_id(headers, session_id.unwrap_or("default"));
+        let tenant = state.ledger.get_or_create(&tenant_id);
+        *tenant.limit.lock().unwrap() = new_limit;
+        println!("🛡️ [哨兵] 租户 {} 的熔断阈值已更新为: {}{}", tenant_id, currency_symbol, new_limit);
+        Ok(json!({ "tenant_id": tenant_id, "limit": new_limit }))
+    } else {
+        *state.ledger.default_limit.lock().unwrap() = new_limit;
+        println!("🛡️ [哨兵] 默认熔断阈值已更新为: {}{}", currency_symbol, new_limit);
+        Ok(json!({ "limit": new_limit }))
     }
 }
 
 #[axum::debug_handler]
 async fn update_limit(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Json(payload): Json<Value>,
 ) -> impl IntoResponse {
-    if let Some(new_limit) = payload["limit"].as_f64() {
-        let mut limit = state.budget_limit.lock().unwrap();
-        *limit = new_limit;
-        let currency_symbol = if state.client.currency_base == "USD" { "$" } else { "￥" };
-        println!("🛡️ [哨兵] 熔断阈值已更新为: {}{}", currency_symbol, new_limit);
-        return (axum::http::StatusCode::OK, "限额更新成功").into_response();
+    match apply_set_limit(&state, &headers, &payload).await {
+        Ok(_) => (axum::http::StatusCode::OK, "限额更新成功").into_response(),
+        Err(_) => (axum::http::StatusCode::BAD_REQUEST, "无效的限额数值").into_response(),
+    }
+}
+
+/// 🆕 [WS 控制协议] `reset_cost` 的核心逻辑，抽出来供 HTTP 端点与 WebSocket `reset_cost` 命令共用
+async fn apply_reset_cost(state: &Arc<AppState>, headers: &HeaderMap, payload: &Value) -> Value {
+    let currency_symbol = if state.client.currency_base == "USD" { "$" } else { "￥" };
+
+    if let Some(session_id) = payload["session_id"].as_str().filter(|s| !s.is_empty()) {
+        let tenant_id = budget::resolve_tenant_id(headers, session_id);
+        state.ledger.get_or_create(&tenant_id).reset();
+        println!("💰 [哨兵] 租户 {} 的累计费用已重置为: {}{}", tenant_id, currency_symbol, 0.0);
+        json!({ "tenant_id": tenant_id })
+    } else {
+        state.ledger.reset_global();
+        // 📊 [烧钱速率预测] 成本归零后清空采样窗口，避免下一次读数算出一次虚假的负速率
+        state.burn_rate.reset();
+        println!("💰 [哨兵] 全局累计费用已重置为: {}{}", currency_symbol, 0.0);
+        json!({})
     }
-    (axum::http::StatusCode::BAD_REQUEST, "无效的限额数值").into_response()
 }
 
 #[axum::debug_handler]
 async fn reset_cost(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
 ) -> impl IntoResponse {
-    state.total_cost.store(0, std::sync::atomic::Ordering::Relaxed);
-    let currency_symbol = if state.client.currency_base == "USD" { "$" } else { "￥" };
-    println!("💰 [哨兵] 累计费用已重置为: {}{}", currency_symbol, 0.0);
+    // 兼容历史调用：请求体可以为空（只重置全局），也可以带 session_id（定向重置某个租户）
+    let payload: Value = serde_json::from_slice(&body).unwrap_or(json!({}));
+    apply_reset_cost(&state, &headers, &payload).await;
+
     Json(json!({
         "success": true,
         "message": "累计费用已重置为 0"
     }))
 }
 
+// 📡 [跨实例计费总线] 从 JetStream 重放指定时间点之后的历史计费事件，供刚连接的仪表盘补读
 #[axum::debug_handler]
-async fn refresh_prices(
+async fn billing_replay(
     State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    println!("🔄 [哨兵] 收到刷新价格缓存请求...");
-    
+    let since = params
+        .get("since")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+
+    if !state.billing_bus.is_enabled() {
+        return Json(json!({ "enabled": false, "events": [] }));
+    }
+
+    let events = state.billing_bus.replay_since(since).await;
+    Json(json!({ "enabled": true, "events": events }))
+}
+
+/// 把 `bucket` 查询参数（如 `"1h"`、`"15m"`、`"1d"`）解析成秒数；无单位后缀时按秒处理，无法解析时回退到 1 小时
+fn parse_bucket_secs(raw: &str) -> u64 {
+    let raw = raw.trim();
+    let (number_part, unit) = match raw.chars().last() {
+        Some(c) if c.is_ascii_alphabetic() => (&raw[..raw.len() - c.len_utf8()], c),
+        _ => (raw, 's'),
+    };
+    let Ok(number) = number_part.parse::<u64>() else { return 3600 };
+    let multiplier = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return 3600,
+    };
+    (number * multiplier).max(1)
+}
+
+// 🧾 [审计导出] 从本地追加式审计日志（含已压缩归档）按时间桶聚合出花费 / token / 请求次数，供离线复盘
+#[axum::debug_handler]
+async fn get_analytics(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let from = params.get("from").and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+    let to = params.get("to").and_then(|v| v.parse::<u64>().ok()).unwrap_or(now);
+    let bucket_secs = params.get("bucket").map(|v| parse_bucket_secs(v)).unwrap_or(3600);
+
+    let buckets = audit::query_analytics(from, to, bucket_secs).await;
+    Json(json!({ "from": from, "to": to, "bucket_secs": bucket_secs, "buckets": buckets }))
+}
+
+// 💰 [按客户端分账] 列出当前已知的全部客户端（API key / 来源 IP / session）及其累计花费与限额
+#[axum::debug_handler]
+async fn list_clients(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let clients: Vec<Value> = state.ledger.snapshot().into_iter().map(|(client_id, spend, limit)| {
+        json!({
+            "client_id": client_id,
+            "spend": spend,
+            "limit": limit,
+            "remaining": (limit - spend).max(0.0),
+        })
+    }).collect();
+
+    Json(json!({ "clients": clients }))
+}
+
+// 💰 [按客户端分账] 查询单个客户端的累计花费、限额和剩余额度
+#[axum::debug_handler]
+async fn get_client_cost(
+    AxumPath(client_id): AxumPath<String>,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let tenant = state.ledger.get_or_create(&client_id);
+    let spend = tenant.spend();
+    let limit = tenant.limit();
+
+    Json(json!({
+        "client_id": client_id,
+        "spend": spend,
+        "limit": limit,
+        "remaining": (limit - spend).max(0.0),
+    }))
+}
+
+// 📊 [烧钱速率告警] 配置 80% 预警比例 / 突增倍数 / 可选 webhook，字段缺省则保留原值
+#[axum::debug_handler]
+async fn update_alert_config(
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<Value>,
+) -> impl IntoResponse {
+    let approaching_ratio = payload.get("approaching_ratio").and_then(|v| v.as_f64());
+    let spike_multiplier = payload.get("spike_multiplier").and_then(|v| v.as_f64());
+    let webhook_url = payload.get("webhook_url").and_then(|v| v.as_str()).map(|s| s.to_string());
+
+    state.alerts.configure(approaching_ratio, spike_multiplier, webhook_url);
+    println!("🔔 [告警配置] 已更新: approaching_ratio={:?}, spike_multiplier={:?}", approaching_ratio, spike_multiplier);
+
+    Json(json!({ "success": true }))
+}
+
+/// 🛡️ [真熔断] 熔断器发生状态迁移时调用，把 Closed/Open/HalfOpen 广播给灵动岛前端
+fn broadcast_circuit_state(state: &Arc<AppState>, tenant_id: &str, circuit_state: budget::CircuitState, cost: f64, limit: f64) {
+    let state_str = match circuit_state {
+        budget::CircuitState::Closed => "closed",
+        budget::CircuitState::Open => "open",
+        budget::CircuitState::HalfOpen => "half_open",
+    };
+    println!("🛡️ [熔断状态] 租户 {} -> {}", tenant_id, state_str);
+    let msg = json!({
+        "type": "circuit_break",
+        "tenant_id": tenant_id,
+        "state": state_str,
+        "cost": cost,
+        "limit": limit,
+    });
+    if let Err(e) = state.ws_tx.send(msg) {
+        println!("❌ [熔断状态] WebSocket 广播失败: {}", e);
+    }
+}
+
+/// 📊 [烧钱速率告警] 采样一次最新成本，把产生的告警事件广播给 WebSocket，并可选 POST 到 webhook
+fn dispatch_alerts(state: &Arc<AppState>, tenant_id: &str, current_cost: f64, limit: f64, model: &str) {
+    let events = state.alerts.sample(tenant_id, current_cost, limit, model);
+    for event in events {
+        println!("🔔 [告警] {}", event);
+        if let Err(e) = state.ws_tx.send(event.clone()) {
+            println!("❌ [告警] WebSocket 广播失败: {}", e);
+        }
+        if let Some(url) = state.alerts.webhook_url() {
+            tokio::spawn(async move {
+                let http = reqwest::Client::new();
+                if let Err(e) = http.post(&url).json(&event).send().await {
+                    println!("⚠️ [告警] Webhook 推送失败: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// 🆕 [WS 控制协议] `refresh_prices` 的核心逻辑，抽出来供 HTTP 端点与 WebSocket `refresh_prices` 命令共用
+async fn apply_refresh_prices(state: &Arc<AppState>) -> Result<Value, String> {
     match state.client.get_all_prices_from_redis().await {
         Ok(prices) => {
             let mut guard = state.price_cache.lock().unwrap();
             *guard = prices;
             println!("✅ [哨兵] 价格缓存已刷新，当前支持 {} 个模型", guard.len());
-            Json(json!({
-                "success": true,
-                "message": format!("成功刷新 {} 个模型价格", guard.len()),
-                "count": guard.len()
-            }))
+            Ok(json!({ "count": guard.len() }))
         }
         Err(e) => {
             println!("❌ [哨兵] 刷新价格缓存失败: {}", e);
-            Json(json!({
-                "success": false,
-                "message": format!("刷新失败: {}", e)
-            }))
+            Err(e.to_string())
         }
     }
 }
 
+#[axum::debug_handler]
+async fn refresh_prices(
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    println!("🔄 [哨兵] 收到刷新价格缓存请求...");
+
+    match apply_refresh_prices(&state).await {
+        Ok(result) => Json(json!({
+            "success": true,
+            "message": format!("成功刷新 {} 个模型价格", result["count"]),
+            "count": result["count"]
+        })),
+        Err(e) => Json(json!({
+            "success": false,
+            "message": format!("刷新失败: {}", e)
+        })),
+    }
+}
+
+/// 🔐 [完整性校验] 核对当前 Redis 里的价格和上次 `sync_litellm_prices` 落盘的 Merkle 根是否一致
+async fn verify_price_integrity_handler(
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    match state.client.verify_price_integrity().await {
+        Ok(result) => Json(result),
+        Err(e) => Json(json!({
+            "matches": false,
+            "reason": format!("校验失败: {}", e)
+        })),
+    }
+}
+
 #[axum::debug_handler]
 async fn get_chat_history(
     AxumPath(session_id): AxumPath<String>,
@@ -666,17 +1299,55 @@ async fn get_chat_history(
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_socket(socket, state))
+    ws.on_upgrade(move |socket| handle_socket(socket, state, headers))
 }
 
-async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
+/// 🆕 [WS 控制协议] 处理一条入站控制命令，复用既有 HTTP 端点背后的逻辑，返回要回送的 ack
+async fn dispatch_ws_command(state: &Arc<AppState>, headers: &HeaderMap, command: &Value, subscribed_session: &mut Option<String>) -> Value {
+    let cmd = command["cmd"].as_str().unwrap_or("");
+    match cmd {
+        "set_limit" => match apply_set_limit(state, headers, command).await {
+            Ok(result) => json!({ "type": "ack", "cmd": cmd, "ok": true, "result": result }),
+            Err(e) => json!({ "type": "ack", "cmd": cmd, "ok": false, "error": e }),
+        },
+        "reset_cost" => {
+            let result = apply_reset_cost(state, headers, command).await;
+            json!({ "type": "ack", "cmd": cmd, "ok": true, "result": result })
+        }
+        "refresh_prices" => match apply_refresh_prices(state).await {
+            Ok(result) => json!({ "type": "ack", "cmd": cmd, "ok": true, "result": result }),
+            Err(e) => json!({ "type": "ack", "cmd": cmd, "ok": false, "error": e }),
+        },
+        "subscribe" => {
+            let session_id = command["session_id"].as_str().filter(|s| !s.is_empty()).map(|s| s.to_string());
+            *subscribed_session = session_id.clone();
+            json!({ "type": "ack", "cmd": cmd, "ok": true, "result": { "session_id": session_id } })
+        }
+        _ => json!({ "type": "ack", "cmd": cmd, "ok": false, "error": "未知命令" }),
+    }
+}
+
+async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>, headers: HeaderMap) {
     let mut rx = state.ws_tx.subscribe();
-    
+    // 🆕 [WS 控制协议] `subscribe` 命令落地后，这条连接只接收该 session 相关的 billing 事件；
+    // 未订阅时保持旧行为，广播一切
+    let mut subscribed_session: Option<String> = None;
+
     loop {
         tokio::select! {
             msg = rx.recv() => {
                 if let Ok(msg) = msg {
+                    // 🆕 [WS 控制协议] 只对 billing 事件按 session_id 过滤，告警/熔断等全局事件仍然广播给所有连接
+                    let is_billing = msg.get("type").and_then(|v| v.as_str()) == Some("billing");
+                    if is_billing {
+                        if let Some(session_id) = &subscribed_session {
+                            if msg.get("session_id").and_then(|v| v.as_str()) != Some(session_id.as_str()) {
+                                continue;
+                            }
+                        }
+                    }
                     if socket.send(Message::Text(msg.to_string())).await.is_err() {
                         break;
                     }
@@ -692,6 +1363,16 @@ async fn handle_socket(mut socket: WebSocket, state: Arc<AppState>) {
                     Message::Close(_) => {
                         break;
                     }
+                    Message::Text(text) => {
+                        let Ok(command) = serde_json::from_str::<Value>(&text) else {
+                            let _ = socket.send(Message::Text(json!({ "type": "ack", "ok": false, "error": "无效的 JSON 指令" }).to_string())).await;
+                            continue;
+                        };
+                        let ack = dispatch_ws_command(&state, &headers, &command, &mut subscribed_session).await;
+                        if socket.send(Message::Text(ack.to_string())).await.is_err() {
+                            break;
+                        }
+                    }
                     _ => {}
                 }
             }
@@ -0,0 +1,151 @@
+// 📊 [可观测性] Prometheus 风格的指标采集器
+//
+// 设计上尽量贴近 Prometheus 文本格式的最小实现：固定边界的桶用 AtomicU64 计数，
+// 避免引入额外的直方图依赖。所有写入路径都是无锁的原子操作，适合热路径调用。
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 延迟直方图的桶边界（毫秒）；+Inf 桶不在表里，由 `Histogram` 额外追加
+const LATENCY_BUCKETS_MS: &[f64] = &[50.0, 100.0, 250.0, 500.0, 1000.0, 2000.0, 5000.0];
+
+/// 单次请求成本直方图的桶边界；成本按各模型的展示币种记录（元或美元），不按 currency_base 统一换算
+const COST_BUCKETS: &[f64] = &[0.0001, 0.001, 0.01, 0.1, 1.0];
+
+/// 固定边界直方图：每个桶是一个 "le"（小于等于）计数器，配合 `_sum` / `_count`。
+struct Histogram {
+    bounds: &'static [f64],
+    // bounds.len() 个有限桶 + 1 个 +Inf 桶
+    buckets: Vec<AtomicU64>,
+    sum_bits: AtomicU64, // f64 的 bit pattern 没法原子加，这里用定点放大后转 u64 存储
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        let buckets = (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect();
+        Histogram {
+            bounds,
+            buckets,
+            sum_bits: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// 记录一次观测值：命中每一个 `le` >= value 的桶，并更新 `_sum` / `_count`
+    fn observe(&self, value: f64) {
+        for (i, bound) in self.bounds.iter().enumerate() {
+            if value <= *bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        // +Inf 桶总是命中
+        self.buckets[self.bounds.len()].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        // 用定点数（放大 1e6 倍）累加，避免浮点 CAS 的复杂度
+        self.sum_bits.fetch_add((value * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("# HELP {} {}\n", name, help));
+        out.push_str(&format!("# TYPE {} histogram\n", name));
+        for (i, bound) in self.bounds.iter().enumerate() {
+            out.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name,
+                bound,
+                self.buckets[i].load(Ordering::Relaxed)
+            ));
+        }
+        out.push_str(&format!(
+            "{}_bucket{{le=\"+Inf\"}} {}\n",
+            name,
+            self.buckets[self.bounds.len()].load(Ordering::Relaxed)
+        ));
+        out.push_str(&format!(
+            "{}_sum {}\n",
+            name,
+            self.sum_bits.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        ));
+        out.push_str(&format!("{}_count {}\n", name, self.count.load(Ordering::Relaxed)));
+        out
+    }
+}
+
+/// 🆕 进程级指标注册表，挂在 `AppState` 上，由流式/非流式计费分支共同写入
+pub struct Metrics {
+    pub prompt_tokens_total: AtomicU64,
+    pub completion_tokens_total: AtomicU64,
+    // 成本按 1e12 定点放大存储，和 `TenantLedger` 的定点单位保持一致
+    pub cost_total_fixed: AtomicU64,
+    chat_latency: Histogram,
+    request_cost: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            prompt_tokens_total: AtomicU64::new(0),
+            completion_tokens_total: AtomicU64::new(0),
+            cost_total_fixed: AtomicU64::new(0),
+            chat_latency: Histogram::new(LATENCY_BUCKETS_MS),
+            request_cost: Histogram::new(COST_BUCKETS),
+        }
+    }
+
+    pub fn record_tokens(&self, prompt: u64, completion: u64) {
+        self.prompt_tokens_total.fetch_add(prompt, Ordering::Relaxed);
+        self.completion_tokens_total.fetch_add(completion, Ordering::Relaxed);
+    }
+
+    pub fn record_cost(&self, cost: f64) {
+        self.cost_total_fixed
+            .fetch_add((cost * 1_000_000_000_000.0) as u64, Ordering::Relaxed);
+        self.request_cost.observe(cost);
+    }
+
+    pub fn record_latency_ms(&self, latency_ms: f64) {
+        self.chat_latency.observe(latency_ms);
+    }
+
+    /// 渲染为 Prometheus 文本暴露格式（`text/plain; version=0.0.4`）
+    pub fn render(&self, budget_limit: f64) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP deepsentine_prompt_tokens_total Cumulative prompt tokens billed.\n");
+        out.push_str("# TYPE deepsentine_prompt_tokens_total counter\n");
+        out.push_str(&format!(
+            "deepsentine_prompt_tokens_total {}\n",
+            self.prompt_tokens_total.load(Ordering::Relaxed)
+        ));
+
+        out.push_str("# HELP deepsentine_completion_tokens_total Cumulative completion tokens billed.\n");
+        out.push_str("# TYPE deepsentine_completion_tokens_total counter\n");
+        out.push_str(&format!(
+            "deepsentine_completion_tokens_total {}\n",
+            self.completion_tokens_total.load(Ordering::Relaxed)
+        ));
+
+        out.push_str("# HELP deepsentine_cost_total Cumulative cost summed over completed requests.\n");
+        out.push_str("# TYPE deepsentine_cost_total counter\n");
+        out.push_str(&format!(
+            "deepsentine_cost_total {}\n",
+            self.cost_total_fixed.load(Ordering::Relaxed) as f64 / 1_000_000_000_000.0
+        ));
+
+        out.push_str("# HELP deepsentine_budget_limit Current configured budget ceiling.\n");
+        out.push_str("# TYPE deepsentine_budget_limit gauge\n");
+        out.push_str(&format!("deepsentine_budget_limit {}\n", budget_limit));
+
+        out.push_str(&self.chat_latency.render(
+            "deepsentine_chat_completion_latency_ms",
+            "Upstream chat_completion latency in milliseconds.",
+        ));
+        out.push_str(&self.request_cost.render(
+            "deepsentine_request_cost",
+            "Per-request cost in each model's display currency (not converted to one currency).",
+        ));
+
+        out
+    }
+}
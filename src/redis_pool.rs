@@ -0,0 +1,39 @@
+// 🏊 [连接池] 手写的 `bb8::ManageConnection`，包装 `redis::aio::ConnectionManager`
+//
+// 现成的 `bb8-redis::RedisConnectionManager` 内部用的是 `MultiplexedConnection`，
+// 一旦连接断开整条连接就报废，得靠 bb8 把它判成 broken 再重新 `connect()`。这里换成
+// `redis::aio::ConnectionManager`：它自带断线自动重连 + 指数退避，坏掉的连接会在
+// 下一次使用时自愈，因此 `has_broken()` 可以放心地一直返回 `false`，把"连接要不要扔掉"
+// 这件事完全交给 ConnectionManager 自己处理，而不是让池子表面上看起来一直健康。
+
+use bb8::ManageConnection;
+use redis::aio::ConnectionManager;
+use redis::{Client as RedisClient, RedisError};
+
+pub struct RedisPoolManager {
+    client: RedisClient,
+}
+
+impl RedisPoolManager {
+    pub fn new(url: &str) -> Result<Self, RedisError> {
+        Ok(RedisPoolManager { client: RedisClient::open(url)? })
+    }
+}
+
+#[async_trait::async_trait]
+impl ManageConnection for RedisPoolManager {
+    type Connection = ConnectionManager;
+    type Error = RedisError;
+
+    async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+        self.client.get_connection_manager().await
+    }
+
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        redis::cmd("PING").query_async::<_, ()>(conn).await
+    }
+
+    fn has_broken(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+}
@@ -0,0 +1,399 @@
+// 🗄️ [存储后端] 把价格 / 聊天历史的持久化抽成两个 trait，Redis 和 Postgres 各实现一套
+//
+// 之前所有持久化都是散落在 `client.rs` 里的具体 `redis::cmd(...)` 调用，想换一套存储
+// 就得到处改。这里拆出 `PriceStore`（价格读写）和 `HistoryStore`（聊天历史读写，带 TTL）
+// 两个 async trait，Redis 实现只是把原来的逻辑原样搬过来；新增的 Postgres 实现给那些已经
+// 在跑 Postgres、不想受 Redis 24 小时淘汰窗口限制的部署一个可查询、持久化的替代方案，
+// 通过 `STORAGE_BACKEND=redis|postgres` 环境变量二选一。
+
+use crate::crypto::MessageCipher;
+use crate::redis_pool::RedisPoolManager;
+use crate::types::{Currency, ModelPricing, PriceUnit, PricingCatalog};
+use anyhow::anyhow;
+use redis::AsyncCommands;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use rust_decimal::prelude::*;
+
+/// 🗄️ 价格读写的存储抽象：当前价格表，不含历史时间序列
+#[async_trait::async_trait]
+pub trait PriceStore: Send + Sync {
+    async fn get_all_prices(&self) -> Result<HashMap<String, ModelPricing>, anyhow::Error>;
+    async fn set_price(&self, model_id: &str, input_price: Decimal, output_price: Decimal, vendor: &str, currency: Currency) -> Result<(), anyhow::Error>;
+}
+
+impl<'a> PricingCatalog<'a> {
+    /// 🆕 [价格目录] `PricingCatalog` 本身只管查找，不重复一套加载逻辑——真正的 JSON/DB 加载就是
+    /// 这里：直接委托给调用方传入的 `PriceStore`（Redis DB0 或 Postgres，由 `STORAGE_BACKEND` 选择）。
+    /// 返回拥有所有权的表，调用方借出去构造 `PricingCatalog::new(&table)` 即可
+    pub async fn load(store: &dyn PriceStore) -> Result<HashMap<String, ModelPricing>, anyhow::Error> {
+        store.get_all_prices().await
+    }
+}
+
+/// 🗄️ 聊天历史读写的存储抽象：按 session 追加消息（带 TTL）/ 按 session 读回
+#[async_trait::async_trait]
+pub trait HistoryStore: Send + Sync {
+    async fn append_message(&self, session_id: &str, message: &Value, ttl_secs: u64) -> Result<(), anyhow::Error>;
+    async fn load_session(&self, session_id: &str) -> Result<Vec<Value>, anyhow::Error>;
+}
+
+// ============================================================================
+// Redis 实现：原样搬运 client.rs 里原来的 DB0/DB1 逻辑
+// ============================================================================
+
+pub struct RedisPriceStore {
+    pool: Arc<Mutex<Option<bb8::Pool<RedisPoolManager>>>>,
+    message_cipher: MessageCipher,
+}
+
+impl RedisPriceStore {
+    pub fn new(pool: Arc<Mutex<Option<bb8::Pool<RedisPoolManager>>>>, message_cipher: MessageCipher) -> Self {
+        Self { pool, message_cipher }
+    }
+
+    fn pool(&self) -> Option<bb8::Pool<RedisPoolManager>> {
+        self.pool.lock().unwrap().clone()
+    }
+}
+
+/// 🐛 [修复] `price:*` 前缀同时被 `price:history:<model>`（ZSET）和 `price:root`/`price:leaves`
+/// 占用；对非 STRING 类型的 key 发 `GET` 会触发 WRONGTYPE。抽成独立函数方便单测覆盖这条判断，
+/// 不用起一个真 Redis 去验证「ZSET 类型的 key 应该被跳过」这件事。
+fn is_price_value_type(redis_type: &str) -> bool {
+    redis_type == "string"
+}
+
+#[async_trait::async_trait]
+impl PriceStore for RedisPriceStore {
+    async fn get_all_prices(&self) -> Result<HashMap<String, ModelPricing>, anyhow::Error> {
+        let Some(pool) = self.pool() else { return Ok(HashMap::new()) };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB0)连接失败: {}", e))?;
+        let keys: Vec<String> = redis::cmd("KEYS").arg("price:*").query_async(&mut *conn).await?;
+
+        let mut prices = HashMap::new();
+        for key in keys {
+            // 🐛 [修复] `price:*` 这个前缀同时被 price:history:<model>（ZSET）占用；对它 GET 会触发
+            // WRONGTYPE 并通过 `?` 炸掉整个 get_all_prices 调用，所以先用 TYPE 只留下字符串类型的 key。
+            // price:root / price:leaves 也是字符串，会通过 TYPE 过滤，之后因为缺少价格字段被跳过
+            let key_type: String = redis::cmd("TYPE").arg(&key).query_async(&mut *conn).await?;
+            if !is_price_value_type(&key_type) {
+                continue;
+            }
+
+            let value: Option<String> = redis::cmd("GET").arg(&key).query_async(&mut *conn).await?;
+            if let Some(v) = value {
+                if let Ok(json) = serde_json::from_str::<Value>(&self.message_cipher.decrypt(&v)) {
+                    if let (Some(input_price), Some(output_price)) = (json["input_price"].as_f64(), json["output_price"].as_f64()) {
+                        let model_id = key.trim_start_matches("price:");
+                        // 🐛 [修复] currency 现在是真正落盘的字段，不再写死 Usd；老记录没有这个
+                        // 字段时（写入于本次修复之前）退回 Usd，和它们当初实际写入时的隐含假设一致
+                        let currency = json["currency"]
+                            .as_str()
+                            .and_then(|s| s.parse::<Currency>().ok())
+                            .unwrap_or(Currency::Usd);
+                        prices.insert(model_id.to_string(), ModelPricing {
+                            input_price: Decimal::from_f64(input_price).unwrap_or(Decimal::ZERO),
+                            output_price: Decimal::from_f64(output_price).unwrap_or(Decimal::ZERO),
+                            currency,
+                            price_scale: 6,
+                            stored_unit: PriceUnit::PerToken,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(prices)
+    }
+
+    async fn set_price(&self, model_id: &str, input_price: Decimal, output_price: Decimal, vendor: &str, currency: Currency) -> Result<(), anyhow::Error> {
+        let Some(pool) = self.pool() else { return Ok(()) };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB0)连接失败: {}", e))?;
+        let value = serde_json::json!({
+            "vendor": vendor,
+            "input_price": input_price.to_f64().unwrap_or(0.0),
+            "output_price": output_price.to_f64().unwrap_or(0.0),
+            "currency": currency.to_string(),
+        });
+        let _: () = redis::cmd("SET")
+            .arg(format!("price:{}", model_id))
+            .arg(self.message_cipher.encrypt(&value.to_string()))
+            .query_async(&mut *conn)
+            .await?;
+        Ok(())
+    }
+}
+
+pub struct RedisHistoryStore {
+    pool: Arc<Mutex<Option<bb8::Pool<RedisPoolManager>>>>,
+    message_cipher: MessageCipher,
+}
+
+impl RedisHistoryStore {
+    pub fn new(pool: Arc<Mutex<Option<bb8::Pool<RedisPoolManager>>>>, message_cipher: MessageCipher) -> Self {
+        Self { pool, message_cipher }
+    }
+
+    fn pool(&self) -> Option<bb8::Pool<RedisPoolManager>> {
+        self.pool.lock().unwrap().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl HistoryStore for RedisHistoryStore {
+    async fn append_message(&self, session_id: &str, message: &Value, ttl_secs: u64) -> Result<(), anyhow::Error> {
+        let Some(pool) = self.pool() else { return Ok(()) };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB1)连接失败: {}", e))?;
+        let key = format!("sentinel:chat:{}", session_id);
+
+        let stored_value = self.message_cipher.encrypt(&message.to_string());
+        let _: () = conn.rpush(&key, stored_value).await?;
+        let _: () = conn.expire(&key, ttl_secs as i64).await?;
+        Ok(())
+    }
+
+    async fn load_session(&self, session_id: &str) -> Result<Vec<Value>, anyhow::Error> {
+        let Some(pool) = self.pool() else { return Ok(vec![]) };
+        let mut conn = pool.get().await.map_err(|e| anyhow!("获取 Redis 连接池(DB1)连接失败: {}", e))?;
+        let key = format!("sentinel:chat:{}", session_id);
+
+        let msgs: Vec<String> = conn.lrange(&key, 0, -1).await?;
+        Ok(msgs.into_iter().filter_map(|m| serde_json::from_str(&self.message_cipher.decrypt(&m)).ok()).collect())
+    }
+}
+
+// ============================================================================
+// Postgres 实现：给已经在跑 Postgres、不想受 Redis 24h 淘汰窗口限制的部署用
+// ============================================================================
+
+/// 🧊 [TTL 清扫] 后台定期清扫任务的执行间隔（chat_history 没有原生 TTL，靠这个任务模拟过期）
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
+
+pub struct PostgresPriceStore {
+    database_url: String,
+    pool: tokio::sync::Mutex<Option<sqlx::PgPool>>,
+}
+
+impl PostgresPriceStore {
+    pub fn new(database_url: String) -> Self {
+        Self { database_url, pool: tokio::sync::Mutex::new(None) }
+    }
+
+    async fn pool(&self) -> Result<sqlx::PgPool, anyhow::Error> {
+        let mut guard = self.pool.lock().await;
+        if let Some(pool) = guard.as_ref() {
+            return Ok(pool.clone());
+        }
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(10)
+            .connect(&self.database_url)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS model_prices (
+                model_id TEXT PRIMARY KEY,
+                input_price DOUBLE PRECISION NOT NULL,
+                output_price DOUBLE PRECISION NOT NULL,
+                vendor TEXT NOT NULL,
+                currency TEXT NOT NULL DEFAULT 'USD',
+                updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        // 🐛 [修复] 老部署的表是修 currency 之前建的，没有这一列；新增时给个和历史隐含假设
+        // 一致的默认值，已有行的 currency 会被回填成 'USD'
+        sqlx::query("ALTER TABLE model_prices ADD COLUMN IF NOT EXISTS currency TEXT NOT NULL DEFAULT 'USD'")
+            .execute(&pool)
+            .await?;
+        *guard = Some(pool.clone());
+        Ok(pool)
+    }
+}
+
+#[async_trait::async_trait]
+impl PriceStore for PostgresPriceStore {
+    async fn get_all_prices(&self) -> Result<HashMap<String, ModelPricing>, anyhow::Error> {
+        let pool = self.pool().await?;
+        let rows: Vec<(String, f64, f64, String)> = sqlx::query_as("SELECT model_id, input_price, output_price, currency FROM model_prices")
+            .fetch_all(&pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(model_id, input_price, output_price, currency)| {
+                let price_info = ModelPricing {
+                    input_price: Decimal::from_f64(input_price).unwrap_or(Decimal::ZERO),
+                    output_price: Decimal::from_f64(output_price).unwrap_or(Decimal::ZERO),
+                    currency: currency.parse::<Currency>().unwrap_or(Currency::Usd),
+                    price_scale: 6,
+                    stored_unit: PriceUnit::PerToken,
+                };
+                (model_id, price_info)
+            })
+            .collect())
+    }
+
+    async fn set_price(&self, model_id: &str, input_price: Decimal, output_price: Decimal, vendor: &str, currency: Currency) -> Result<(), anyhow::Error> {
+        let pool = self.pool().await?;
+        sqlx::query(
+            "INSERT INTO model_prices (model_id, input_price, output_price, vendor, currency, updated_at)
+             VALUES ($1, $2, $3, $4, $5, now())
+             ON CONFLICT (model_id) DO UPDATE SET
+                input_price = EXCLUDED.input_price,
+                output_price = EXCLUDED.output_price,
+                vendor = EXCLUDED.vendor,
+                currency = EXCLUDED.currency,
+                updated_at = now()",
+        )
+        .bind(model_id)
+        .bind(input_price.to_f64().unwrap_or(0.0))
+        .bind(output_price.to_f64().unwrap_or(0.0))
+        .bind(vendor)
+        .bind(currency.to_string())
+        .execute(&pool)
+        .await?;
+        Ok(())
+    }
+}
+
+pub struct PostgresHistoryStore {
+    database_url: String,
+    pool: tokio::sync::Mutex<Option<sqlx::PgPool>>,
+    sweeper_started: AtomicBool,
+}
+
+impl PostgresHistoryStore {
+    pub fn new(database_url: String) -> Self {
+        Self { database_url, pool: tokio::sync::Mutex::new(None), sweeper_started: AtomicBool::new(false) }
+    }
+
+    async fn pool(&self) -> Result<sqlx::PgPool, anyhow::Error> {
+        let mut guard = self.pool.lock().await;
+        if let Some(pool) = guard.as_ref() {
+            return Ok(pool.clone());
+        }
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(10)
+            .connect(&self.database_url)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS chat_history (
+                seq BIGSERIAL PRIMARY KEY,
+                session_id TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS chat_history_session_seq_idx ON chat_history (session_id, seq)")
+            .execute(&pool)
+            .await?;
+        *guard = Some(pool.clone());
+        Ok(pool)
+    }
+
+    /// 🧊 [TTL 清扫] 第一次真正写入时才把后台清扫任务拉起来，只拉一次
+    fn ensure_sweeper(&self, pool: sqlx::PgPool, ttl_secs: u64) {
+        if self.sweeper_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let cutoff_secs = ttl_secs as f64;
+                let result = sqlx::query("DELETE FROM chat_history WHERE created_at < now() - make_interval(secs => $1)")
+                    .bind(cutoff_secs)
+                    .execute(&pool)
+                    .await;
+                if let Err(e) = result {
+                    println!("⚠️ [存储/Postgres] 聊天历史 TTL 清扫失败: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl HistoryStore for PostgresHistoryStore {
+    async fn append_message(&self, session_id: &str, message: &Value, ttl_secs: u64) -> Result<(), anyhow::Error> {
+        let pool = self.pool().await?;
+        self.ensure_sweeper(pool.clone(), ttl_secs);
+
+        sqlx::query("INSERT INTO chat_history (session_id, payload) VALUES ($1, $2)")
+            .bind(session_id)
+            .bind(message.to_string())
+            .execute(&pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn load_session(&self, session_id: &str) -> Result<Vec<Value>, anyhow::Error> {
+        let pool = self.pool().await?;
+        let rows: Vec<(String,)> = sqlx::query_as("SELECT payload FROM chat_history WHERE session_id = $1 ORDER BY seq ASC")
+            .bind(session_id)
+            .fetch_all(&pool)
+            .await?;
+        Ok(rows.into_iter().filter_map(|(payload,)| serde_json::from_str(&payload).ok()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 🐛 [回归] chunk2-5: `price:history:<model>` 是 ZSET，不能 GET；只有 STRING 类型的 key
+    /// 会被当作可 GET 的 key（`price:root`/`price:leaves` 也是 STRING，由后续的价格字段检查跳过）
+    #[test]
+    fn is_price_value_type_only_accepts_string() {
+        assert!(is_price_value_type("string"));
+        assert!(!is_price_value_type("zset"));
+        assert!(!is_price_value_type("hash"));
+        assert!(!is_price_value_type("none"));
+    }
+
+    /// 🐛 [回归] chunk2-4/2-5: `set_price` 写入的 JSON 形状，经加密/解密后要能被
+    /// `get_all_prices` 的解析逻辑原样读回，不丢 input_price/output_price
+    #[test]
+    fn price_value_round_trips_through_cipher_and_json() {
+        let cipher = MessageCipher::from_env(); // 未设置 SENTINEL_ENCRYPTION_KEY 时是直通实现
+        let input_price = Decimal::new(15, 7); // 0.0000015
+        let output_price = Decimal::new(3, 6); // 0.000003
+
+        let value = serde_json::json!({
+            "vendor": "litellm_auto",
+            "input_price": input_price.to_f64().unwrap_or(0.0),
+            "output_price": output_price.to_f64().unwrap_or(0.0),
+            "currency": Currency::Cny.to_string(),
+        });
+        let stored = cipher.encrypt(&value.to_string());
+        let decrypted = cipher.decrypt(&stored);
+
+        let json: Value = serde_json::from_str(&decrypted).expect("解密后应为合法 JSON");
+        let round_tripped_input = json["input_price"].as_f64().expect("input_price 应可读回");
+        let round_tripped_output = json["output_price"].as_f64().expect("output_price 应可读回");
+        let round_tripped_currency = json["currency"].as_str().and_then(|s| s.parse::<Currency>().ok());
+
+        assert_eq!(Decimal::from_f64(round_tripped_input).unwrap_or(Decimal::ZERO), input_price);
+        assert_eq!(Decimal::from_f64(round_tripped_output).unwrap_or(Decimal::ZERO), output_price);
+        assert_eq!(round_tripped_currency, Some(Currency::Cny));
+    }
+
+    /// 🐛 [回归] chunk3-4: 修复前写入的老记录没有 `currency` 字段，读回时要落回 Usd
+    /// （和它们当初写入时的隐含假设一致），而不是解析失败或 panic
+    #[test]
+    fn missing_currency_field_falls_back_to_usd() {
+        let json = serde_json::json!({
+            "vendor": "litellm_auto",
+            "input_price": 0.000001,
+            "output_price": 0.000002,
+        });
+        let currency = json["currency"]
+            .as_str()
+            .and_then(|s| s.parse::<Currency>().ok())
+            .unwrap_or(Currency::Usd);
+        assert_eq!(currency, Currency::Usd);
+    }
+}
@@ -0,0 +1,120 @@
+// 🧊 [限流保护] 上游 429 / Retry-After 自动冻结与重试
+//
+// 当某个 provider（dashscope/zhipu/deepseek）返回 429 时，记录一个
+// "frozen_until" 时间戳，在冻结期内的所有请求要么排队等待解冻，要么在
+// 等待时间超过上限时直接拒绝，避免对已经被限流的后端继续施压。
+
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// 单次等待冻结解除的上限，超过这个时长就直接拒绝调用方
+const MAX_FREEZE_WAIT: Duration = Duration::from_secs(30);
+
+/// 同一 provider 连续 429 的重试上限，超过后放弃
+const MAX_RETRIES: u32 = 5;
+
+pub struct ProviderThrottle {
+    // provider -> 解冻时间
+    frozen_until: DashMap<String, Instant>,
+    // provider -> 连续 429 次数（用于指数退避）
+    consecutive_429s: DashMap<String, AtomicU32>,
+}
+
+pub enum ThrottleWait {
+    /// 当前未冻结，可以立即发起请求
+    Ready,
+    /// 曾经冻结，但等待后已经解除，调用方应当重试
+    WaitedThenReady,
+    /// 冻结剩余时间超过了 `MAX_FREEZE_WAIT`，直接拒绝
+    Rejected { retry_after_secs: u64 },
+}
+
+impl ProviderThrottle {
+    pub fn new() -> Self {
+        ProviderThrottle {
+            frozen_until: DashMap::new(),
+            consecutive_429s: DashMap::new(),
+        }
+    }
+
+    /// 在发起上游请求前调用：如果 provider 处于冻结期，等待解冻或直接拒绝
+    pub async fn wait_if_frozen(&self, provider: &str) -> ThrottleWait {
+        let frozen_until = self.frozen_until.get(provider).map(|r| *r);
+
+        if let Some(until) = frozen_until {
+            let now = Instant::now();
+            if until <= now {
+                return ThrottleWait::Ready;
+            }
+
+            let remaining = until - now;
+            if remaining > MAX_FREEZE_WAIT {
+                return ThrottleWait::Rejected {
+                    retry_after_secs: remaining.as_secs(),
+                };
+            }
+
+            tokio::time::sleep(remaining).await;
+            return ThrottleWait::WaitedThenReady;
+        }
+
+        ThrottleWait::Ready
+    }
+
+    /// 收到上游 429 时调用：解析 `Retry-After` 并记录冻结截止时间，
+    /// 并叠加基于连续 429 次数的指数退避 + 抖动。
+    pub fn freeze_on_429(&self, provider: &str, retry_after: Option<Duration>) -> Duration {
+        let attempt = {
+            let counter = self
+                .consecutive_429s
+                .entry(provider.to_string())
+                .or_insert_with(|| AtomicU32::new(0));
+            counter.fetch_add(1, Ordering::Relaxed) + 1
+        };
+
+        let backoff = exponential_backoff_with_jitter(attempt);
+        let delay = retry_after.map(|d| d.max(backoff)).unwrap_or(backoff);
+
+        self.frozen_until
+            .insert(provider.to_string(), Instant::now() + delay);
+        delay
+    }
+
+    /// 上游请求成功（非 429）时调用，清零连续失败计数
+    pub fn record_success(&self, provider: &str) {
+        self.consecutive_429s.remove(provider);
+    }
+
+    pub fn should_give_up(&self, provider: &str) -> bool {
+        self.consecutive_429s
+            .get(provider)
+            .map(|c| c.load(Ordering::Relaxed) >= MAX_RETRIES)
+            .unwrap_or(false)
+    }
+}
+
+/// 指数退避（500ms * 2^attempt，封顶 30s）叠加 ±20% 抖动
+fn exponential_backoff_with_jitter(attempt: u32) -> Duration {
+    let base_ms = 500u64.saturating_mul(1u64 << attempt.min(6));
+    let capped_ms = base_ms.min(30_000);
+    // 用系统时钟的纳秒位做抖动源，避免为了 ±20% 抖动额外引入 rand 依赖
+    let now_nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let jitter_pct = (now_nanos % 40) as i64 - 20;
+    let jittered_ms = (capped_ms as i64 + capped_ms as i64 * jitter_pct / 100).max(100) as u64;
+    Duration::from_millis(jittered_ms)
+}
+
+/// 解析 `Retry-After` 响应头：支持秒数形式和 HTTP-date 形式
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    httpdate::parse_http_date(value.trim())
+        .ok()
+        .and_then(|when| when.duration_since(std::time::SystemTime::now()).ok())
+}
@@ -1,18 +1,266 @@
+use anyhow::anyhow;
+use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
 
-// 🎯 配置项：是否强制国内模型显示人民币
-// true：所有国内模型（qwen/glm/yi/deepseek）都显示人民币，数值会自动换算（乘7.2）
-// false：按数据库原始数值显示
-const FORCE_CNY_FOR_CHINESE_MODELS: bool = true;
+/// 💱 [币种] 目前只支持 USD/CNY；`FxRateOracle::convert` 也只在这两种币种之间换算
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Currency {
+    #[serde(rename = "USD")]
+    Usd,
+    #[serde(rename = "CNY")]
+    Cny,
+}
+
+impl std::fmt::Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Currency::Usd => write!(f, "USD"),
+            Currency::Cny => write!(f, "CNY"),
+        }
+    }
+}
+
+impl std::str::FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "USD" => Ok(Currency::Usd),
+            "CNY" => Ok(Currency::Cny),
+            other => Err(anyhow!("不支持的币种: {}", other)),
+        }
+    }
+}
+
+/// 💱 [币种识别] 一条按顺序生效的识别规则，命中就判定为对应币种并停止继续匹配
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CurrencyRule {
+    /// 模型名（已转小写）包含该子串
+    ModelContains { pattern: String, currency: Currency },
+    /// 单价超过该阈值——兜底规则，通常放在规则列表最后
+    PriceThreshold { threshold: Decimal, currency: Currency },
+}
+
+/// 💱 [厂商覆盖] 某厂商入库价格的币种和对外展示币种不一致时的覆盖项
+/// （目前只有 DeepSeek：库里存的是美金价，对外按人民币展示，按 `FxRateOracle` 的牌价换算）
+#[derive(Debug, Clone, Deserialize)]
+struct VendorOverride {
+    vendor_pattern: String,
+    /// 该厂商价格表里实际存的币种
+    stored_currency: Currency,
+    /// 对外展示时要换算成的币种；和 `stored_currency` 不同时由 `FxRateOracle` 按实时牌价换算
+    display_currency: Currency,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+struct CurrencyResolverConfig {
+    #[serde(default)]
+    rules: Vec<CurrencyRule>,
+    #[serde(default)]
+    vendor_overrides: Vec<VendorOverride>,
+}
+
+/// 💱 [币种识别] 取代原来散落在三个计费函数里的 `model_lower.contains("qwen"|"glm"|...)`
+/// 硬编码判断：持有一组按顺序生效的规则 + 厂商覆盖表，可以从配置（`CURRENCY_RESOLVER_CONFIG`
+/// 环境变量，一份 JSON）加载；未配置或解析失败时退回内置默认规则。
+/// 三个计费函数现在都只调用一次 `resolve_declared`，不会再出现同一个模型在不同函数里判定结果不一致的
+/// 情况（迁移前 `calculate_real_time_cost` 把 DeepSeek 当美金处理，`calculate_actual_cost`
+/// 却强制把它换算成人民币——这正是集中注册表要消灭的那种复制粘贴漂移）。
+#[derive(Debug, Clone)]
+pub struct CurrencyResolver {
+    rules: Vec<CurrencyRule>,
+    vendor_overrides: Vec<VendorOverride>,
+}
+
+impl CurrencyResolver {
+    /// 内置默认规则，判断条件取自迁移前的三份硬编码逻辑
+    fn default_rules() -> Vec<CurrencyRule> {
+        vec![
+            CurrencyRule::ModelContains { pattern: "qwen".to_string(), currency: Currency::Cny },
+            CurrencyRule::ModelContains { pattern: "glm".to_string(), currency: Currency::Cny },
+            CurrencyRule::ModelContains { pattern: "zhipu".to_string(), currency: Currency::Cny },
+            CurrencyRule::ModelContains { pattern: "yi-".to_string(), currency: Currency::Cny },
+            CurrencyRule::ModelContains { pattern: "deepseek".to_string(), currency: Currency::Usd },
+            CurrencyRule::PriceThreshold { threshold: Decimal::new(1, 2), currency: Currency::Cny },
+        ]
+    }
+
+    fn default_vendor_overrides() -> Vec<VendorOverride> {
+        vec![VendorOverride {
+            vendor_pattern: "deepseek".to_string(),
+            stored_currency: Currency::Usd,
+            display_currency: Currency::Cny,
+        }]
+    }
+
+    /// 从 `CURRENCY_RESOLVER_CONFIG` 环境变量（`{"rules": [...], "vendor_overrides": [...]}`）
+    /// 加载；未配置、为空或解析失败时退回内置默认规则
+    pub fn from_env() -> Self {
+        let fallback = Self { rules: Self::default_rules(), vendor_overrides: Self::default_vendor_overrides() };
+
+        let Some(raw) = std::env::var("CURRENCY_RESOLVER_CONFIG").ok().filter(|s| !s.is_empty()) else {
+            return fallback;
+        };
+
+        match serde_json::from_str::<CurrencyResolverConfig>(&raw) {
+            Ok(cfg) if !cfg.rules.is_empty() => Self { rules: cfg.rules, vendor_overrides: cfg.vendor_overrides },
+            Ok(_) => {
+                println!("⚠️ [币种识别] CURRENCY_RESOLVER_CONFIG 未提供任何规则，回退到内置默认规则");
+                fallback
+            }
+            Err(e) => {
+                println!("⚠️ [币种识别] 解析 CURRENCY_RESOLVER_CONFIG 失败，回退到内置默认规则: {}", e);
+                fallback
+            }
+        }
+    }
+
+    /// 依次按规则判定某个模型"入库价格的币种"和"对外展示的币种"；`reference_price` 供
+    /// `PriceThreshold` 兜底规则使用。两者不一致时（目前只有 DeepSeek），调用方要拿
+    /// `FxRateOracle::convert` 按实时牌价换算，而不是像迁移前那样乘一个写死的 `7.2`。
+    /// 没有命中厂商覆盖时两者相同，`convert` 对相同币种是原样返回的恒等操作。
+    pub fn resolve(&self, model_id: &str, reference_price: Decimal) -> (Currency, Currency) {
+        let model_lower = model_id.to_lowercase();
+
+        for ov in &self.vendor_overrides {
+            if model_lower.contains(ov.vendor_pattern.as_str()) {
+                return (ov.stored_currency, ov.display_currency);
+            }
+        }
+
+        for rule in &self.rules {
+            match rule {
+                CurrencyRule::ModelContains { pattern, currency } => {
+                    if model_lower.contains(pattern.as_str()) {
+                        return (*currency, *currency);
+                    }
+                }
+                CurrencyRule::PriceThreshold { threshold, currency } => {
+                    if reference_price > *threshold {
+                        return (*currency, *currency);
+                    }
+                }
+            }
+        }
+
+        (Currency::Usd, Currency::Usd)
+    }
 
-#[allow(dead_code, unused_variables)]
+    /// 💱 [价格目录] `ModelPricing.currency` 是入库时就显式记下的币种，不需要再靠模型名/单价去猜——
+    /// 这里直接拿它当 `stored_currency`，只用厂商覆盖表判断展示币种是否要换算；
+    /// 命中厂商覆盖但记录的币种和覆盖表预期的不一致时以记录为准，只打日志示警（大概率是价格源配错了）
+    pub fn resolve_declared(&self, model_id: &str, declared_currency: Currency) -> (Currency, Currency) {
+        let model_lower = model_id.to_lowercase();
 
+        for ov in &self.vendor_overrides {
+            if model_lower.contains(ov.vendor_pattern.as_str()) {
+                if declared_currency != ov.stored_currency {
+                    println!(
+                        "⚠️ [币种识别] 模型 {} 的价格记录声明币种为 {}，与厂商覆盖表预期的 {} 不一致，以价格记录为准",
+                        model_id, declared_currency, ov.stored_currency
+                    );
+                }
+                return (declared_currency, ov.display_currency);
+            }
+        }
+
+        (declared_currency, declared_currency)
+    }
+}
+
+// 💰 [精确计费] input_price/output_price 改用 Decimal：单价乘海量 token 数、再逐 chunk
+// 累加这类运算在 f64 下会悄悄积累舍入误差，Decimal 是精确十进制运算，加多少次都不会漂移
+//
+// 🆕 [价格目录] 在原来的双字段基础上补上 currency（显式存，不再靠猜）、price_scale（展示用
+// 小数位数）、stored_unit（input_price/output_price 到底是"每 token"还是"每百万 token"，
+// 解决了散落在各处注释里反复提到的那个除不除 1,000,000 的歧义）。
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct PriceInfo {
-    pub input_price: f64,
-    pub output_price: f64,
+pub struct ModelPricing {
+    pub input_price: Decimal,
+    pub output_price: Decimal,
+    #[serde(default = "default_pricing_currency")]
+    pub currency: Currency,
+    #[serde(default = "default_price_scale")]
+    pub price_scale: u32,
+    #[serde(default)]
+    pub stored_unit: PriceUnit,
+}
+
+fn default_pricing_currency() -> Currency {
+    Currency::Usd
+}
+
+fn default_price_scale() -> u32 {
+    6
+}
+
+/// 🆕 [价格目录] `ModelPricing.input_price`/`output_price` 的计价单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceUnit {
+    PerToken,
+    PerMillionTokens,
+}
+
+impl Default for PriceUnit {
+    fn default() -> Self {
+        PriceUnit::PerToken
+    }
+}
+
+impl ModelPricing {
+    /// 不管 `stored_unit` 存的是哪种单位，统一折算成"每 token"单价，调用方不用再关心单位
+    pub fn per_token_input(&self) -> Decimal {
+        match self.stored_unit {
+            PriceUnit::PerToken => self.input_price,
+            PriceUnit::PerMillionTokens => self.input_price / Decimal::from(1_000_000u32),
+        }
+    }
+
+    pub fn per_token_output(&self) -> Decimal {
+        match self.stored_unit {
+            PriceUnit::PerToken => self.output_price,
+            PriceUnit::PerMillionTokens => self.output_price / Decimal::from(1_000_000u32),
+        }
+    }
+}
+
+/// 🆕 [价格目录] 包一层 `&HashMap<String, ModelPricing>`，把原来在每个计费函数里各自重复一遍的
+/// "先精确匹配，再在 key 里找包含关系"的归一化查找逻辑收敛成一个方法。
+/// 从 JSON/DB 加载这份表本身委托给 [`crate::storage::PriceStore`]（`PricingCatalog::load`
+/// 是对它的薄封装）——这里只管查找，不重复一套加载逻辑
+pub struct PricingCatalog<'a> {
+    entries: &'a HashMap<String, ModelPricing>,
+}
+
+impl<'a> PricingCatalog<'a> {
+    pub fn new(entries: &'a HashMap<String, ModelPricing>) -> Self {
+        PricingCatalog { entries }
+    }
+
+    /// 先按归一化后的模型名精确匹配，找不到再退化成双向包含匹配（原来四处重复的 fallback 逻辑）
+    pub fn lookup(&self, model_id: &str) -> Option<&ModelPricing> {
+        let normalized_model = normalize_model_name(model_id);
+
+        if let Some(price) = self.entries.get(&normalized_model) {
+            return Some(price);
+        }
+
+        let model_lower = normalized_model.to_lowercase();
+        let matching_key = self.entries.keys().find(|key| {
+            let key_lower = key.to_lowercase();
+            key_lower.contains(&model_lower) || model_lower.contains(&key_lower)
+        })?;
+        self.entries.get(matching_key)
+    }
+}
+
+/// 💰 [精确计费] 把成本四舍五入到指定的小数位数，仅用于展示（内部累计仍用未舍入的 Decimal）
+pub fn round_for_display(amount: Decimal, digits: u32) -> Decimal {
+    amount.round_dp(digits)
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -26,7 +274,7 @@ pub struct Usage {
 
 /// ✅ 从流式响应中计算实时成本（基于 tiktoken 的实时精确计算）
 /// 🆕 [性能优化] 接受外部传入的 bpe 编码器，避免重复加载
-pub fn calculate_real_time_cost(chunk: &Value, model_id: &str, price_cache: &HashMap<String, PriceInfo>, bpe: &tiktoken_rs::CoreBPE) -> (f64, String) {
+pub fn calculate_real_time_cost(chunk: &Value, model_id: &str, price_cache: &HashMap<String, ModelPricing>, bpe: &tiktoken_rs::CoreBPE, resolver: &CurrencyResolver, oracle: &crate::fx_oracle::FxRateOracle) -> (Decimal, Currency) {
     // 尝试从 chunk 中提取内容并使用 tiktoken 进行精确计算
     if let Some(choices) = chunk.get("choices").and_then(|c| c.as_array()) {
         if let Some(choice) = choices.first() {
@@ -35,53 +283,21 @@ pub fn calculate_real_time_cost(chunk: &Value, model_id: &str, price_cache: &Has
                     // 🆕 [性能优化] 直接使用外部传入的 bpe 编码器（全局复用）
                     let tokens = bpe.encode_with_special_tokens(content);
                     let token_count = tokens.len();
-                    
-                    let normalized_model = normalize_model_name(model_id);
-                    
-                    // 从价格缓存中获取价格信息
-                    let price_info = price_cache.get(&normalized_model).cloned().or_else(|| {
-                        let matching_key = price_cache.keys().find(|key| {
-                            let key_lower = key.to_lowercase();
-                            let model_lower = normalized_model.to_lowercase();
-                            key_lower.contains(&model_lower) || model_lower.contains(&key_lower)
-                        });
-                        
-                        if let Some(key) = matching_key {
-                            price_cache.get(key).cloned()
-                        } else {
-                            None
-                        }
-                    });
-                    
+
+                    // 🆕 [价格目录] 精确匹配 + 包含匹配的归一化查找收敛到 PricingCatalog::lookup
+                    let catalog = PricingCatalog::new(price_cache);
+                    let price_info = catalog.lookup(model_id).cloned();
+
                     if let Some(ref price) = price_info {
                         // 计算成本：只计算输出token（completion tokens）
-                        let cost_value = token_count as f64 * price.output_price;
-                        
-                        // 智能币种识别
-                        let model_lower = model_id.to_lowercase();
-                        
-                        // 优化的币种识别逻辑
-                        let is_cny = if model_lower.contains("qwen") || 
-                                     model_lower.contains("glm") || 
-                                     model_lower.contains("zhipu") || 
-                                     model_lower.contains("yi-") {
-                            // 1. 这些厂商在你的库里存的确实是"大数"，认定为人民币
-                            true 
-                        } else if model_lower.contains("deepseek") {
-                            // 2. 特殊情况：你的数据库里 DeepSeek 是美金价
-                            // 为了显示有意义的数值，DeepSeek应该显示为美金
-                            false
-                        } else if price.input_price > 0.01 {
-                            // 3. 兜底逻辑：只要价格数值大，不管叫啥名，都是人民币
-                            true
-                        } else {
-                            // 4. 其余全是美金
-                            false
-                        };
-                        
-                        let currency = if is_cny { "CNY".to_string() } else { "USD".to_string() };
-                        
-                        return (cost_value, currency);
+                        let cost_value = Decimal::from(token_count as u64) * price.per_token_output();
+
+                        // 💱 [币种识别] 币种直接取价格记录上显式存的 currency，不再靠模型名/单价去猜；
+                        // 存的币种和要展示的币种不一致时交给汇率预言机按实时牌价换算
+                        let (stored_currency, display_currency) = resolver.resolve_declared(model_id, price.currency);
+                        let converted = oracle.convert(cost_value, stored_currency, display_currency);
+
+                        return (converted, display_currency);
                     }
                 }
             }
@@ -91,7 +307,7 @@ pub fn calculate_real_time_cost(chunk: &Value, model_id: &str, price_cache: &Has
     // 如果无法从 chunk 中提取内容，则尝试解析 usage 字段作为后备方案
     if let Some(usage_val) = chunk.get("usage") {
         // 如果 usage 字段本身就是 null，直接跳过
-        if usage_val.is_null() { return (0.0, "USD".to_string()); }
+        if usage_val.is_null() { return (Decimal::ZERO, Currency::Usd); }
         
         // 尝试自动解析。如果自动解析失败，我们手动抓取字段（这样最稳！）
         let (prompt, completion) = if let Ok(u) = serde_json::from_value::<Usage>(usage_val.clone()) {
@@ -110,12 +326,12 @@ pub fn calculate_real_time_cost(chunk: &Value, model_id: &str, price_cache: &Has
                 completion_tokens: Some(completion),
                 total_tokens: Some(prompt + completion),
             };
-            return calculate_actual_cost(model_id, &usage, price_cache);
+            return calculate_actual_cost(model_id, &usage, price_cache, resolver, oracle);
         }
     }
-    
+
     // 中间过程的包（null 或没有 usage），直接返回 0.0，不要报错
-    (0.0, "USD".to_string())
+    (Decimal::ZERO, Currency::Usd)
 }
 
 /// ✅ 解析 Usage 包
@@ -129,18 +345,22 @@ pub fn extract_usage_from_chunk(chunk: &Value) -> Option<(u64, u64)> {
     }
 }
 
-pub fn estimate_cost(model: &str, payload: &Value) -> f64 {
+/// 💰 [精确计费] 粗略估算（基于字符数，不是精确 tokenizer），所以内部仍用 f64 做启发式
+/// 运算，只在返回前转成 Decimal，和其它三个函数统一对外契约
+pub fn estimate_cost(model: &str, payload: &Value) -> Decimal {
     let model_lower = model.to_lowercase();
-    
+
     let (text_tokens, image_count) = extract_tokens_and_images(payload);
-    
-    if model_lower.contains("vl") {
+
+    let estimated = if model_lower.contains("vl") {
         let est_tokens = text_tokens + (image_count as f64 * 1000.0);
         (est_tokens / 1000.0) * 0.003
     } else {
         let est_tokens = text_tokens * 1.3;
         (est_tokens / 1000.0) * 0.8
-    }
+    };
+
+    Decimal::from_f64(estimated).unwrap_or(Decimal::ZERO)
 }
 
 fn extract_tokens_and_images(payload: &Value) -> (f64, usize) {
@@ -287,88 +507,138 @@ pub fn parse_request(request_body: &str) -> Result<ParsedRequest, ParseError> {
     })
 }
 
-pub fn calculate_actual_cost(model: &str, usage: &Usage, price_cache: &HashMap<String, PriceInfo>) -> (f64, String) {
-    let input_tokens = usage.prompt_tokens.unwrap_or(0) as f64;
-    let output_tokens = usage.completion_tokens.unwrap_or(0) as f64;
-    
+pub fn calculate_actual_cost(model: &str, usage: &Usage, price_cache: &HashMap<String, ModelPricing>, resolver: &CurrencyResolver, oracle: &crate::fx_oracle::FxRateOracle) -> (Decimal, Currency) {
+    let input_tokens = Decimal::from(usage.prompt_tokens.unwrap_or(0));
+    let output_tokens = Decimal::from(usage.completion_tokens.unwrap_or(0));
+
     let normalized_model = normalize_model_name(model);
-    
+
     println!("🔍 [DEBUG] 计算成本 - 原始模型: '{}', 归一化后: '{}', 输入tokens: {}, 输出tokens: {}", model, normalized_model, input_tokens, output_tokens);
     println!("🔍 [DEBUG] 价格缓存中的模型列表: {:?}", price_cache.keys().collect::<Vec<_>>());
-    
-    // 🆕 [强化匹配] 先尝试精确匹配，再尝试包含匹配
-    let price = price_cache.get(&normalized_model).cloned().or_else(|| {
-        // 如果精确匹配失败，尝试查找包含该模型名的 key
-        let matching_key = price_cache.keys().find(|key| {
-            let key_lower = key.to_lowercase();
-            let model_lower = normalized_model.to_lowercase();
-            key_lower.contains(&model_lower) || model_lower.contains(&key_lower)
-        });
-        
-        if let Some(key) = matching_key {
-            println!("✅ [DEBUG] 通过包含匹配找到价格: {} -> {}", normalized_model, key);
-            price_cache.get(key).cloned()
-        } else {
-            println!("⚠️ 哨兵提示：未找到模型 {} 的价格情报", normalized_model);
-            Some(PriceInfo { input_price: 0.00001, output_price: 0.00001 })
-        }
+
+    // 🆕 [价格目录] 精确匹配 + 包含匹配的归一化查找收敛到 PricingCatalog::lookup
+    let catalog = PricingCatalog::new(price_cache);
+    let price = catalog.lookup(model).cloned().or_else(|| {
+        println!("⚠️ 哨兵提示：未找到模型 {} 的价格情报", normalized_model);
+        Some(ModelPricing { input_price: Decimal::new(1, 5), output_price: Decimal::new(1, 5), currency: Currency::Usd, price_scale: 6, stored_unit: PriceUnit::PerToken })
     });
-    
+
     let (cost, currency) = if let Some(ref price_info) = price {
-        // 🕵️‍♂️ 智能币种侦察兵
-        let model_lower = model.to_lowercase();
-        
-        // 优化的币种识别逻辑
-        let is_cny = if model_lower.contains("qwen") || 
-                     model_lower.contains("glm") || 
-                     model_lower.contains("zhipu") || 
-                     model_lower.contains("yi-") ||
-                     model_lower.contains("deepseek") {
-            // 1. 这些厂商的模型都显示为人民币
-            true 
-        } else if price_info.input_price > 0.01 {
-            // 3. 兜底逻辑：只要价格数值大，不管叫啥名，都是人民币
-            true
-        } else {
-            // 4. 其余全是美金
-            false
-        };
-        
         // ⚡️ 修正：直接使用每token价格（不再除以1,000,000）
-        let cost_value = input_tokens * price_info.input_price
-                          + output_tokens * price_info.output_price;
-        
-        if FORCE_CNY_FOR_CHINESE_MODELS && (model_lower.contains("qwen") || 
-                                            model_lower.contains("glm") || 
-                                            model_lower.contains("zhipu") || 
-                                            model_lower.contains("yi-") || 
-                                            model_lower.contains("deepseek")) {
-            // 配置项：强制国内模型显示人民币
-            // 如果是Qwen/GLM/Yi，直接显示CNY（数值已经是人民币）
-            // 如果是DeepSeek，显示CNY但数值要乘7.2（因为库里是美金价）
-            if model_lower.contains("deepseek") {
-                (cost_value * 7.2, "CNY".to_string())
-            } else {
-                (cost_value, "CNY".to_string())
-            }
-        } else {
-            // 使用新的识别逻辑
-            if is_cny {
-                (cost_value, "CNY".to_string())
-            } else {
-                (cost_value, "USD".to_string())
-            }
-        }
+        let cost_value = input_tokens * price_info.per_token_input()
+                          + output_tokens * price_info.per_token_output();
+
+        // 💱 [币种识别] 币种直接取价格记录上显式存的 currency，不再靠模型名/单价去猜；
+        // 存的币种和要展示的币种不一致时交给汇率预言机按实时牌价换算
+        let (stored_currency, display_currency) = resolver.resolve_declared(model, price_info.currency);
+        let converted = oracle.convert(cost_value, stored_currency, display_currency);
+
+        (converted, display_currency)
     } else {
         // 保底单价（每token）
-        (0.0, "USD".to_string())
+        (Decimal::ZERO, Currency::Usd)
     };
-    
-    println!("🔍 [DEBUG] 实时计算出的成本: {:.9}, 币种: {}", cost, currency);
-    
+
+    println!("🔍 [DEBUG] 实时计算出的成本: {}, 币种: {}", cost, currency);
+
     (cost, currency)
 }
 
+/// 🆕 [流式计费汇总] 一次完整请求结束时对外交付的计费记录：调用方不用再自己把逐 chunk 的
+/// `(cost, currency)` 元组求和，直接拿这一份即可
+#[derive(Debug, Clone, Serialize)]
+pub struct BillingSummary {
+    pub model: String,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: Decimal,
+    pub currency: Currency,
+    /// `true` 表示 `cost`/`output_tokens` 来自 tiktoken 估算（流还没收到权威 usage 包）；
+    /// `false` 表示已经用服务端上报的真实 token 数重算过
+    pub estimated: bool,
+}
+
+/// 🆕 [流式计费汇总] 喂入每个 SSE chunk 的增量内容：内部用 tiktoken 增量编码、累加运行中的
+/// completion token 数和 Decimal 成本（prompt token 数只来自权威 usage）；收到收尾包里的权威 `usage` 后调用
+/// [`reconcile_with_usage`]，优先采用服务端上报的 token 数重算一次，取代 tiktoken 估算。
+/// `finish()` 产出最终的 [`BillingSummary`]。
+pub struct StreamCostAccumulator<'a> {
+    model: String,
+    price_cache: &'a HashMap<String, ModelPricing>,
+    resolver: &'a CurrencyResolver,
+    oracle: &'a crate::fx_oracle::FxRateOracle,
+    bpe: &'a tiktoken_rs::CoreBPE,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    cost: Decimal,
+    currency: Currency,
+    estimated: bool,
+}
+
+impl<'a> StreamCostAccumulator<'a> {
+    pub fn new(
+        model: &str,
+        price_cache: &'a HashMap<String, ModelPricing>,
+        resolver: &'a CurrencyResolver,
+        oracle: &'a crate::fx_oracle::FxRateOracle,
+        bpe: &'a tiktoken_rs::CoreBPE,
+    ) -> Self {
+        StreamCostAccumulator {
+            model: model.to_string(),
+            price_cache,
+            resolver,
+            oracle,
+            bpe,
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            cost: Decimal::ZERO,
+            currency: Currency::Usd,
+            estimated: true,
+        }
+    }
+
+    /// 喂入一段增量内容：tiktoken 编码、累加输出 token 数和 Decimal 成本（估算值，尚未被
+    /// 权威 usage 覆盖前，`finish()` 返回的 `BillingSummary.estimated` 会标成 `true`）
+    pub fn push_delta(&mut self, content: &str) {
+        let token_count = self.bpe.encode_with_special_tokens(content).len() as u64;
+        self.completion_tokens += token_count;
+
+        let catalog = PricingCatalog::new(self.price_cache);
+        if let Some(price) = catalog.lookup(&self.model) {
+            let cost_value = Decimal::from(token_count) * price.per_token_output();
+            let (stored_currency, display_currency) = self.resolver.resolve_declared(&self.model, price.currency);
+            self.cost += self.oracle.convert(cost_value, stored_currency, display_currency);
+            self.currency = display_currency;
+        }
+    }
+
+    /// 收到收尾包里的权威 usage：优先采用服务端上报的 token 数重算成本，不再信任 tiktoken 估算
+    pub fn reconcile_with_usage(&mut self, usage: &Usage) {
+        let prompt_tokens = usage.prompt_tokens.unwrap_or(0);
+        let completion_tokens = usage.completion_tokens.unwrap_or(self.completion_tokens);
+
+        let (cost, currency) = calculate_actual_cost_with_tokens(&self.model, prompt_tokens, completion_tokens, self.price_cache, self.resolver, self.oracle);
+
+        self.prompt_tokens = prompt_tokens;
+        self.completion_tokens = completion_tokens;
+        self.cost = cost;
+        self.currency = currency;
+        self.estimated = false;
+    }
+
+    /// 收尾：产出最终的计费汇总记录
+    pub fn finish(self) -> BillingSummary {
+        BillingSummary {
+            model: self.model,
+            input_tokens: self.prompt_tokens,
+            output_tokens: self.completion_tokens,
+            cost: self.cost,
+            currency: self.currency,
+            estimated: self.estimated,
+        }
+    }
+}
+
 pub fn normalize_model_name(model: &str) -> String {
     let model_lower = model.to_lowercase();
     
@@ -383,81 +653,38 @@ pub fn normalize_model_name(model: &str) -> String {
     normalized
 }
 
-pub fn calculate_actual_cost_with_tokens(model: &str, prompt_tokens: f64, completion_tokens: f64, price_cache: &HashMap<String, PriceInfo>) -> (f64, String) {
+pub fn calculate_actual_cost_with_tokens(model: &str, prompt_tokens: u64, completion_tokens: u64, price_cache: &HashMap<String, ModelPricing>, resolver: &CurrencyResolver, oracle: &crate::fx_oracle::FxRateOracle) -> (Decimal, Currency) {
+    let prompt_tokens = Decimal::from(prompt_tokens);
+    let completion_tokens = Decimal::from(completion_tokens);
     let normalized_model = normalize_model_name(model);
-    
+
     println!("🔍 [DEBUG] 实时计费 - 原始模型: '{}', 归一化后: '{}', 输入tokens: {}, 输出tokens: {}", model, normalized_model, prompt_tokens, completion_tokens);
     println!("🔍 [DEBUG] 价格缓存中的模型列表: {:?}", price_cache.keys().collect::<Vec<_>>());
-    
-    // 🆕 [强化匹配] 先尝试精确匹配，再尝试包含匹配
-    let price = price_cache.get(&normalized_model).cloned().or_else(|| {
-        // 如果精确匹配失败，尝试查找包含该模型名的 key
-        let matching_key = price_cache.keys().find(|key| {
-            let key_lower = key.to_lowercase();
-            let model_lower = normalized_model.to_lowercase();
-            key_lower.contains(&model_lower) || model_lower.contains(&key_lower)
-        });
-        
-        if let Some(key) = matching_key {
-            println!("✅ [DEBUG] 通过包含匹配找到价格: {} -> {}", normalized_model, key);
-            price_cache.get(key).cloned()
-        } else {
-            println!("⚠️ 哨兵提示：未找到模型 {} 的价格情报", normalized_model);
-            Some(PriceInfo { input_price: 0.00001, output_price: 0.00001 })
-        }
+
+    // 🆕 [价格目录] 精确匹配 + 包含匹配的归一化查找收敛到 PricingCatalog::lookup
+    let catalog = PricingCatalog::new(price_cache);
+    let price = catalog.lookup(model).cloned().or_else(|| {
+        println!("⚠️ 哨兵提示：未找到模型 {} 的价格情报", normalized_model);
+        Some(ModelPricing { input_price: Decimal::new(1, 5), output_price: Decimal::new(1, 5), currency: Currency::Usd, price_scale: 6, stored_unit: PriceUnit::PerToken })
     });
-    
+
     let (cost, currency) = if let Some(ref price_info) = price {
-        // 🕵️‍♂️ 智能币种侦察兵
-        let model_lower = model.to_lowercase();
-        
-        // 优化的币种识别逻辑
-        let is_cny = if model_lower.contains("qwen") || 
-                     model_lower.contains("glm") || 
-                     model_lower.contains("zhipu") || 
-                     model_lower.contains("yi-") ||
-                     model_lower.contains("deepseek") {
-            // 1. 这些厂商的模型都显示为人民币
-            true 
-        } else if price_info.input_price > 0.01 {
-            // 3. 兜底逻辑：只要价格数值大，不管叫啥名，都是人民币
-            true
-        } else {
-            // 4. 其余全是美金
-            false
-        };
-        
         // ⚡️ 修正：直接使用每token价格（不再除以1,000,000）
-        let cost_value = prompt_tokens * price_info.input_price
-                          + completion_tokens * price_info.output_price;
-        
-        if FORCE_CNY_FOR_CHINESE_MODELS && (model_lower.contains("qwen") || 
-                                            model_lower.contains("glm") || 
-                                            model_lower.contains("zhipu") || 
-                                            model_lower.contains("yi-") || 
-                                            model_lower.contains("deepseek")) {
-            // 配置项：强制国内模型显示人民币
-            // 如果是Qwen/GLM/Yi，直接显示CNY（数值已经是人民币）
-            // 如果是DeepSeek，显示CNY但数值要乘7.2（因为库里是美金价）
-            if model_lower.contains("deepseek") {
-                (cost_value * 7.2, "CNY".to_string())
-            } else {
-                (cost_value, "CNY".to_string())
-            }
-        } else {
-            // 使用新的识别逻辑
-            if is_cny {
-                (cost_value, "CNY".to_string())
-            } else {
-                (cost_value, "USD".to_string())
-            }
-        }
+        let cost_value = prompt_tokens * price_info.per_token_input()
+                          + completion_tokens * price_info.per_token_output();
+
+        // 💱 [币种识别] 币种直接取价格记录上显式存的 currency，不再靠模型名/单价去猜；
+        // 存的币种和要展示的币种不一致时交给汇率预言机按实时牌价换算
+        let (stored_currency, display_currency) = resolver.resolve_declared(model, price_info.currency);
+        let converted = oracle.convert(cost_value, stored_currency, display_currency);
+
+        (converted, display_currency)
     } else {
         // 保底单价（每token）
-        (0.0, "USD".to_string())
+        (Decimal::ZERO, Currency::Usd)
     };
-    
+
     println!("🔍 [DEBUG] 实时计算出的成本: {}, 币种: {}", cost, currency);
-    
+
     (cost, currency)
 }